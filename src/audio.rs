@@ -0,0 +1,376 @@
+use songbird::input::codecs::{get_codec_registry, get_probe};
+use songbird::tracks::PlayError;
+use std::fs::File;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_OPUS, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+/// File extensions of the formats the bot can play, lowercase. These need to
+/// match the formats enabled for symphonia in Cargo.toml, plus Opus, which
+/// songbird decodes itself.
+pub const EXTENSIONS: &[&str] = &["aac", "flac", "m4a", "mp3", "oga", "ogg", "opus", "wav"];
+
+/// Opus frames songbird can send to Discord as they are: 20 ms at 48 kHz.
+const PASSTHROUGH_FRAME: u64 = 960;
+
+/// Lowercase extension of a path, or "" if it has none.
+pub fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Whether a file is in a format the bot can play, going by its extension.
+pub fn is_supported(path: &Path) -> bool {
+    EXTENSIONS.contains(&extension(path).as_str())
+}
+
+/// What decoding a clip from start to end tells us about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// Short codec name, e.g. "mp3".
+    pub codec: String,
+    pub seconds: f64,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<usize>,
+    /// Loudest sample, from 0 (silence) to 1 (full scale).
+    pub peak: f32,
+    /// Whether the clip is Opus that songbird streams to Discord without
+    /// decoding and re-encoding it, which saves a lot of CPU.
+    pub passthrough: bool,
+    /// Track number from the file's tags, for categories ordered by metadata.
+    pub track_number: Option<u32>,
+    /// What's said in the clip, from the file's tags, see find_transcript.
+    pub transcript: Option<String>,
+}
+
+/// Details about a quip's file, gathered when the library is scanned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioInfo {
+    /// Why the file couldn't be decoded, if it couldn't.
+    pub analysis: Result<Analysis, String>,
+    /// File size in bytes.
+    pub size: u64,
+    /// Last modification as seconds since the Unix epoch, if known.
+    pub modified: Option<i64>,
+}
+
+impl AudioInfo {
+    /// Analyze a file on disk. A file that can't be read is recorded as
+    /// unplayable rather than failing the whole library scan.
+    pub fn read(path: &Path) -> AudioInfo {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => return AudioInfo::unreadable(&e),
+        };
+        let analysis = match File::open(path) {
+            Ok(file) => analyze(Box::new(file), &extension(path)),
+            Err(e) => Err(e.to_string()),
+        };
+        AudioInfo {
+            analysis,
+            size: metadata.len(),
+            modified: modified(&metadata),
+        }
+    }
+
+    /// Details of a file that couldn't even be looked at.
+    pub fn unreadable(error: &std::io::Error) -> AudioInfo {
+        AudioInfo {
+            analysis: Err(format!("The file can't be read: {}", error)),
+            size: 0,
+            modified: None,
+        }
+    }
+}
+
+/// Modification time of a file as seconds since the Unix epoch.
+pub fn modified(metadata: &std::fs::Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64)
+}
+
+/// Decode a clip in full, returning a reason if it isn't audio the bot can
+/// play. The extension is only a hint; the contents decide. Uses songbird's
+/// codecs and formats, so that what works here also plays.
+pub fn analyze(source: Box<dyn MediaSource>, extension: &str) -> Result<Analysis, String> {
+    let invalid = |e: SymphoniaError| format!("The file is not valid audio: {}", e);
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+    let mss = MediaSourceStream::new(source, Default::default());
+    let mut probed = get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(invalid)?;
+    // Tags may come before the container (e.g. ID3 in MP3) or within it.
+    let mut tags: Vec<Tag> = probed
+        .metadata
+        .get()
+        .and_then(|m| m.current().map(|r| r.tags().to_vec()))
+        .unwrap_or_default();
+    let mut format = probed.format;
+    if let Some(revision) = format.metadata().current() {
+        tags.extend_from_slice(revision.tags());
+    }
+
+    let track = format
+        .default_track()
+        .ok_or("The file doesn't contain any audio.")?;
+    let codecs = get_codec_registry();
+    let mut decoder = codecs
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(invalid)?;
+    let codec = codecs
+        .get_codec(track.codec_params.codec)
+        .map(|c| c.short_name.to_string())
+        .unwrap_or_default();
+    let track_id = track.id;
+    let opus = track.codec_params.codec == CODEC_TYPE_OPUS;
+    let mut sample_rate = track.codec_params.sample_rate;
+    let mut channels = track.codec_params.channels.map(|c| c.count());
+
+    let mut frames: u64 = 0;
+    // Packets of other sizes can't be passed through. The last one usually
+    // is one, and songbird puts up with a few.
+    let mut odd_packets = 0;
+    let mut peak: f32 = 0.0;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(invalid(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        if packet.dur != PASSTHROUGH_FRAME {
+            odd_packets += 1;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet here and there is skipped when playing too.
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(invalid(e)),
+        };
+        let spec = *decoded.spec();
+        sample_rate = Some(spec.rate);
+        channels = Some(spec.channels.count());
+        frames += decoded.frames() as u64;
+
+        let buffer =
+            samples.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        if buffer.capacity() < decoded.capacity() * spec.channels.count() {
+            *buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+        }
+        buffer.copy_interleaved_ref(decoded);
+        peak = buffer
+            .samples()
+            .iter()
+            .fold(peak, |peak, s| peak.max(s.abs()));
+    }
+
+    let Some(rate) = sample_rate.filter(|_| frames > 0) else {
+        return Err("The file doesn't contain any audio.".to_string());
+    };
+    Ok(Analysis {
+        codec,
+        seconds: frames as f64 / rate as f64,
+        sample_rate,
+        channels,
+        peak: peak.min(1.0),
+        passthrough: opus && sample_rate == Some(48000) && odd_packets <= 1,
+        track_number: find_track_number(&tags),
+        transcript: find_transcript(&tags),
+    })
+}
+
+/// The transcript among some tags: the lyrics (ID3 "USLT", Vorbis "LYRICS"),
+/// or else a tag called "TRANSCRIPT", e.g. a Vorbis comment or ID3 "TXXX".
+fn find_transcript(tags: &[Tag]) -> Option<String> {
+    let is_transcript = |t: &&Tag| {
+        let key = t.key.to_lowercase();
+        key == "transcript" || key.ends_with(":transcript")
+    };
+    tags.iter()
+        .find(|t| t.std_key == Some(StandardTagKey::Lyrics))
+        .or_else(|| tags.iter().find(is_transcript))
+        .map(|t| t.value.to_string().trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+/// Longest transcript shown, quote marks included. "!info" shows it in an
+/// embed field, the tightest of the places it appears, so it's sized for that.
+pub const MAX_TRANSCRIPT_CHARS: usize = crate::EMBED_FIELD_LIMIT;
+
+/// Quote a transcript for a Discord message, shortened to at most `max_chars`
+/// characters so the message fits. The quote marks count too: a transcript of
+/// many short lines almost doubles in length once quoted.
+pub fn quote_transcript(transcript: &str, max_chars: usize) -> String {
+    let quoted = transcript
+        .lines()
+        .map(|line| format!("> {}", line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if quoted.chars().count() <= max_chars {
+        return quoted;
+    }
+    let mut text: String = quoted.chars().take(max_chars.saturating_sub(1)).collect();
+    // Don't leave a quote mark without a line behind.
+    let trimmed = text.trim_end().trim_end_matches("\n>").trim_end().len();
+    text.truncate(trimmed);
+    text.push('…');
+    text
+}
+
+/// The track number among some tags. Track numbers are often given as e.g.
+/// "3/12", meaning the third of twelve.
+fn find_track_number(tags: &[Tag]) -> Option<u32> {
+    tags.iter()
+        .filter(|t| t.std_key == Some(StandardTagKey::TrackNumber))
+        .find_map(|t| {
+            let value = t.value.to_string();
+            value.split('/').next()?.trim().parse().ok()
+        })
+}
+
+/// Peak level in dBFS, e.g. "-3.0 dBFS".
+pub fn describe_peak(peak: f32) -> String {
+    if peak <= 0.0 {
+        return String::from("silent");
+    }
+    format!("{:.1} dBFS", 20.0 * peak.log10())
+}
+
+/// Why a quip failed to play, in terms that make sense to the people
+/// listening, e.g. "the file is damaged partway through".
+pub fn describe_play_error(error: &PlayError) -> &'static str {
+    match error {
+        PlayError::Create(_) => "the file couldn't be opened; it may have been moved or deleted",
+        PlayError::Parse(_) => {
+            "the file isn't audio I can read; it may be damaged or in an unsupported format"
+        }
+        PlayError::Decode(_) => "the file is damaged partway through",
+        _ => "something went wrong reading the file",
+    }
+}
+
+/// A short silent MP3 for tests: MPEG-1 Layer III frames at 128 kbps and
+/// 44.1 kHz, 1152 samples each.
+#[cfg(test)]
+pub fn test_mp3(frames: usize) -> Vec<u8> {
+    let mut frame = vec![0u8; 417];
+    frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+    frame.repeat(frames)
+}
+
+/// test_mp3 with an ID3v2.3 tag giving its track number, e.g. "3/12".
+#[cfg(test)]
+pub fn test_mp3_track(frames: usize, track: &str) -> Vec<u8> {
+    let mut frame = b"TRCK".to_vec();
+    frame.extend_from_slice(&(track.len() as u32 + 1).to_be_bytes());
+    // No flags, then the text encoding (ISO-8859-1) and the text.
+    frame.extend_from_slice(&[0, 0, 0]);
+    frame.extend_from_slice(track.as_bytes());
+    // The tag size is "synchsafe": 7 bits per byte.
+    let size = frame.len() as u32;
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8));
+    tag.extend(frame);
+    tag.extend(test_mp3(frames));
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use symphonia::core::meta::Value;
+
+    #[test]
+    fn test_analyze() {
+        let analysis = analyze(Box::new(Cursor::new(test_mp3(100))), "mp3").unwrap();
+        assert_eq!(analysis.codec, "mp3");
+        assert_eq!(analysis.sample_rate, Some(44100));
+        assert_eq!(analysis.channels, Some(2));
+        assert_eq!(analysis.peak, 0.0);
+        assert!(!analysis.passthrough);
+        assert_eq!(analysis.track_number, None);
+        // The decoder may hold back a frame or so.
+        assert!((analysis.seconds - 100.0 * 1152.0 / 44100.0).abs() < 0.1);
+
+        assert!(analyze(Box::new(Cursor::new(b"not audio".to_vec())), "mp3").is_err());
+        assert!(analyze(Box::new(Cursor::new(Vec::new())), "mp3").is_err());
+    }
+
+    #[test]
+    fn test_track_number() {
+        let mp3 = test_mp3_track(10, "3/12");
+        let analysis = analyze(Box::new(Cursor::new(mp3)), "mp3").unwrap();
+        assert_eq!(analysis.track_number, Some(3));
+    }
+
+    #[test]
+    fn test_find_transcript() {
+        let tag = |std_key, key: &str, value: &str| Tag::new(std_key, key, Value::from(value));
+        let lyrics = tag(Some(StandardTagKey::Lyrics), "USLT!eng", " I need food! ");
+        let custom = tag(None, "TXXX:Transcript", "Wololo");
+        let title = tag(Some(StandardTagKey::TrackTitle), "TIT2", "Hmm");
+
+        assert_eq!(
+            find_transcript(&[title.clone(), custom.clone(), lyrics]),
+            Some("I need food!".to_string())
+        );
+        assert_eq!(
+            find_transcript(&[title.clone(), custom]),
+            Some("Wololo".to_string())
+        );
+        assert_eq!(find_transcript(&[title]), None);
+        assert_eq!(find_transcript(&[tag(None, "TRANSCRIPT", "  ")]), None);
+    }
+
+    #[test]
+    fn test_quote_transcript() {
+        assert_eq!(quote_transcript("Yes", 100), "> Yes");
+        assert_eq!(quote_transcript("One\nTwo", 100), "> One\n> Two");
+        assert_eq!(quote_transcript("Wololo", 6), "> Wol…");
+        assert_eq!(quote_transcript("a\nb\nc\nd", 8), "> a\n> b…");
+        assert!(quote_transcript(&"a\n".repeat(1000), 750).chars().count() <= 750);
+    }
+
+    #[test]
+    fn test_is_supported() {
+        assert!(is_supported(Path::new("a1/Hmm.mp3")));
+        assert!(is_supported(Path::new("a1/Hmm.OPUS")));
+        assert!(is_supported(Path::new("a1/Hmm.m4a")));
+        assert!(!is_supported(Path::new("a1/notes.txt")));
+        assert!(!is_supported(Path::new("a1/noextension")));
+    }
+
+    #[test]
+    fn test_describe_peak() {
+        assert_eq!(describe_peak(0.0), "silent");
+        assert_eq!(describe_peak(1.0), "0.0 dBFS");
+        assert_eq!(describe_peak(0.5), "-6.0 dBFS");
+    }
+
+    #[test]
+    fn test_describe_play_error() {
+        use std::sync::Arc;
+        let unsupported = || Arc::new(SymphoniaError::Unsupported("codec"));
+        assert!(describe_play_error(&PlayError::Parse(unsupported())).contains("unsupported"));
+        assert!(describe_play_error(&PlayError::Decode(unsupported())).contains("damaged"));
+    }
+}
@@ -0,0 +1,557 @@
+use poise::serenity_prelude::{ChannelId, GuildId, RoleId};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Bot configuration, read from a TOML file (`config.toml` by default). Every
+/// section is optional, so a missing or empty file gives sensible defaults.
+///
+/// Settings can be overridden per guild under `[guilds.<guild id>]`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Default command prefix.
+    pub prefix: String,
+    /// Whether mentioning the bot works as a prefix too.
+    pub mention_prefix: bool,
+    /// Whether command names (and thus quip categories) ignore case.
+    pub case_insensitive_commands: bool,
+    /// Path to the database file. Defaults to "disquip.sqlite3" next to the
+    /// audio directory.
+    pub database: Option<PathBuf>,
+    /// Default cooldowns for playing quips.
+    pub cooldown: Cooldowns,
+    /// How "!r" picks quips.
+    pub random: RandomConfig,
+    /// Limits for quips added with "!upload".
+    pub upload: UploadConfig,
+    /// Limits for playing quips.
+    pub playback: PlaybackConfig,
+    /// Whether to post a quip's transcript, from its tags, when it plays.
+    pub transcripts: bool,
+    /// Whether to keep a "now playing" message with playback controls.
+    pub now_playing: bool,
+    /// Where and how the bot logs.
+    pub logging: LoggingConfig,
+    /// The HTTP endpoint for Prometheus metrics.
+    pub metrics: MetricsConfig,
+    /// The HTTP endpoint for liveness and readiness checks.
+    pub health: HealthConfig,
+    /// Per-category settings, keyed by category (directory) name.
+    pub categories: HashMap<String, CategoryConfig>,
+    /// Per-guild overrides, keyed by guild ID. TOML keys are always strings,
+    /// so the IDs are kept as strings too.
+    pub guilds: HashMap<String, GuildConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prefix: String::from("!"),
+            mention_prefix: true,
+            case_insensitive_commands: true,
+            database: None,
+            cooldown: Cooldowns::default(),
+            random: RandomConfig::default(),
+            upload: UploadConfig::default(),
+            playback: PlaybackConfig::default(),
+            transcripts: false,
+            now_playing: false,
+            logging: LoggingConfig::default(),
+            metrics: MetricsConfig::default(),
+            health: HealthConfig::default(),
+            categories: HashMap::new(),
+            guilds: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct GuildConfig {
+    /// Overrides the top-level prefix for this guild. A prefix set with the
+    /// "!prefix" command takes precedence over both.
+    pub prefix: Option<String>,
+    /// Overrides the top-level cooldowns for this guild.
+    pub cooldown: Option<Cooldowns>,
+    /// Overrides the top-level random settings for this guild.
+    pub random: Option<RandomConfig>,
+    /// Overrides the top-level playback limits for this guild.
+    pub playback: Option<PlaybackConfig>,
+    /// Overrides the top-level transcripts setting for this guild.
+    pub transcripts: Option<bool>,
+    /// Overrides the top-level now-playing setting for this guild.
+    pub now_playing: Option<bool>,
+    /// Members with any of these roles are bot admins for this guild.
+    pub admin_roles: Vec<RoleId>,
+    /// Roles allowed to play and list quips. Empty means everyone.
+    pub quip_roles: Vec<RoleId>,
+    /// Roles allowed to use the Civ commands. Empty means everyone.
+    pub civ_roles: Vec<RoleId>,
+    /// Roles allowed to add quips with "!upload". Empty means only admins.
+    pub upload_roles: Vec<RoleId>,
+    /// Channel where quips uploaded by everyone else are sent for review. If
+    /// unset, only the members above may upload.
+    pub review_channel: Option<ChannelId>,
+    /// Roles allowed to play quips longer than the playback limit without
+    /// "--long". Admins always may.
+    pub long_roles: Vec<RoleId>,
+    /// Text channels the bot listens in. Empty means all of them.
+    pub channels: Vec<ChannelId>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CategoryConfig {
+    /// How the category's quips are numbered.
+    pub order: Order,
+}
+
+/// How the quips in a category are numbered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// By file name, comparing numbers in names by value, so "2 Bar.mp3" comes
+    /// before "10 Foo.mp3".
+    #[default]
+    Natural,
+    /// Oldest file first, so new quips get the next number and existing
+    /// numbers don't change.
+    Modified,
+    /// By the track number in the files' tags (e.g. ID3), then by name. Files
+    /// without one come last.
+    Metadata,
+}
+
+/// Cooldowns for each scope a quip can be played in. A scope without a limit
+/// is not rate limited.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Cooldowns {
+    pub user: Option<Limit>,
+    pub channel: Option<Limit>,
+    pub guild: Option<Limit>,
+}
+
+impl Default for Cooldowns {
+    /// Out of the box, only individual users are limited, which is enough to
+    /// stop someone from spamming "!r".
+    fn default() -> Self {
+        Cooldowns {
+            user: Some(Limit {
+                period: 2.0,
+                burst: 3,
+            }),
+            channel: None,
+            guild: None,
+        }
+    }
+}
+
+/// How "!r" picks quips.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RandomConfig {
+    /// Play every quip once before repeating any (a "shuffle bag").
+    pub shuffle: bool,
+    /// Favor quips that have been played less often.
+    pub weighted: bool,
+    /// Leave out quips that failed to play this many times in a row, until
+    /// they play fine again. 0 never leaves any out.
+    pub broken_after: u32,
+}
+
+impl Default for RandomConfig {
+    fn default() -> Self {
+        RandomConfig {
+            shuffle: true,
+            weighted: false,
+            broken_after: 3,
+        }
+    }
+}
+
+/// Limits for quips added with "!upload".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// Largest file accepted, in bytes.
+    pub max_bytes: u64,
+    /// Longest clip accepted, in seconds.
+    pub max_seconds: f64,
+}
+
+impl Default for UploadConfig {
+    /// Quips are meant to be short, so these are deliberately tight.
+    fn default() -> Self {
+        UploadConfig {
+            max_bytes: 1_000_000,
+            max_seconds: 10.0,
+        }
+    }
+}
+
+/// Limits for playing quips.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PlaybackConfig {
+    /// Quips are cut off after this many seconds, and quips longer than this
+    /// are "long": they only play for members with a long role or when asked
+    /// for with "--long", and then in full. 0 disables the limit.
+    pub max_seconds: f64,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        PlaybackConfig { max_seconds: 30.0 }
+    }
+}
+
+impl PlaybackConfig {
+    /// The limit in seconds, or None if there is none.
+    pub fn limit(&self) -> Option<f64> {
+        (self.max_seconds > 0.0).then_some(self.max_seconds)
+    }
+}
+
+/// Where and how the bot logs. The RUST_LOG environment variable, if set,
+/// overrides the level.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// The least severe level logged: "error", "warn", "info", "debug" or
+    /// "trace". Other crates only log warnings and errors unless this is a
+    /// full filter in RUST_LOG syntax, e.g. "info,songbird=debug".
+    pub level: String,
+    pub format: LogFormat,
+    /// Also log to files in this directory, besides standard output.
+    pub directory: Option<PathBuf>,
+    /// How often to start a new log file.
+    pub rotation: Rotation,
+    /// How many log files to keep. 0 keeps them all.
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: String::from("info"),
+            format: LogFormat::Plain,
+            directory: None,
+            rotation: Rotation::Daily,
+            max_files: 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per event.
+    #[default]
+    Plain,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+/// The HTTP endpoint for Prometheus metrics, served at "/metrics".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Address to listen on. Only local by default.
+    pub listen: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 9184)),
+        }
+    }
+}
+
+/// The HTTP endpoint for liveness and readiness checks, served at
+/// "/health/live" and "/health/ready".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    /// Address to listen on. Only local by default.
+    pub listen: SocketAddr,
+    /// Seconds the Discord gateway may stay disconnected before
+    /// "/health/live" fails. Reconnecting normally takes far less.
+    pub gateway_grace: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            enabled: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 9185)),
+            gateway_grace: 300,
+        }
+    }
+}
+
+/// A token bucket: up to `burst` plays back to back, then one more every
+/// `period` seconds. A `period` of 0 disables the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Limit {
+    pub period: f64,
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+}
+
+fn default_burst() -> u32 {
+    1
+}
+
+impl Config {
+    /// Read the configuration from the given path. A missing file is not an
+    /// error, since every setting has a default.
+    pub fn load(path: &Path) -> Result<Config, crate::Error> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| {
+            crate::Error::Internal(format!("Failed to parse {}: {}", path.display(), e).into())
+        })
+    }
+
+    /// Get the order of the quips in the given category.
+    pub fn order(&self, category: &str) -> Order {
+        self.categories
+            .get(category)
+            .map(|c| c.order)
+            .unwrap_or_default()
+    }
+
+    /// Get the overrides for the given guild, if any.
+    pub fn guild(&self, guild_id: GuildId) -> Option<&GuildConfig> {
+        self.guilds.get(&guild_id.to_string())
+    }
+
+    /// Get the configured prefix for the given guild.
+    pub fn prefix(&self, guild_id: GuildId) -> &str {
+        self.guild(guild_id)
+            .and_then(|g| g.prefix.as_deref())
+            .unwrap_or(&self.prefix)
+    }
+
+    /// Get the cooldowns that apply in the given guild.
+    pub fn cooldowns(&self, guild_id: GuildId) -> &Cooldowns {
+        self.guild(guild_id)
+            .and_then(|g| g.cooldown.as_ref())
+            .unwrap_or(&self.cooldown)
+    }
+
+    /// Get the random settings that apply in the given guild.
+    pub fn random(&self, guild_id: GuildId) -> RandomConfig {
+        self.guild(guild_id)
+            .and_then(|g| g.random)
+            .unwrap_or(self.random)
+    }
+
+    /// Get the playback limits that apply in the given guild.
+    pub fn playback(&self, guild_id: GuildId) -> PlaybackConfig {
+        self.guild(guild_id)
+            .and_then(|g| g.playback)
+            .unwrap_or(self.playback)
+    }
+
+    /// Whether transcripts are shown in the given guild.
+    pub fn transcripts(&self, guild_id: GuildId) -> bool {
+        self.guild(guild_id)
+            .and_then(|g| g.transcripts)
+            .unwrap_or(self.transcripts)
+    }
+
+    /// Whether the given guild has a now-playing message.
+    pub fn now_playing(&self, guild_id: GuildId) -> bool {
+        self.guild(guild_id)
+            .and_then(|g| g.now_playing)
+            .unwrap_or(self.now_playing)
+    }
+
+    /// Whether a member with the given roles has one of the guild's configured
+    /// admin roles.
+    pub fn is_admin(&self, guild_id: GuildId, roles: &[RoleId]) -> bool {
+        self.guild(guild_id)
+            .is_some_and(|g| g.admin_roles.iter().any(|r| roles.contains(r)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_config() {
+        let config: Config = toml::from_str("").unwrap();
+        let guild_id = GuildId::new(1);
+        assert_eq!(
+            config.cooldowns(guild_id).user,
+            Some(Limit {
+                period: 2.0,
+                burst: 3
+            })
+        );
+        assert!(!config.is_admin(guild_id, &[RoleId::new(1)]));
+        assert_eq!(config.prefix(guild_id), "!");
+        assert!(config.mention_prefix);
+        assert_eq!(config.order("a1"), Order::Natural);
+    }
+
+    #[test]
+    fn test_categories() {
+        let config: Config = toml::from_str(
+            r#"
+[categories.a1]
+order = "modified"
+
+[categories.sw]
+order = "metadata"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.order("a1"), Order::Modified);
+        assert_eq!(config.order("sw"), Order::Metadata);
+        assert_eq!(config.order("a2"), Order::Natural);
+        assert!(toml::from_str::<Config>("[categories.a1]\norder = \"random\"").is_err());
+    }
+
+    #[test]
+    fn test_logging() {
+        let config: Config = toml::from_str(
+            r#"
+[logging]
+level = "debug"
+format = "json"
+directory = "logs"
+rotation = "hourly"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.logging,
+            LoggingConfig {
+                level: String::from("debug"),
+                format: LogFormat::Json,
+                directory: Some(PathBuf::from("logs")),
+                rotation: Rotation::Hourly,
+                max_files: 7,
+            }
+        );
+        assert!(toml::from_str::<Config>("[logging]\nformat = \"xml\"").is_err());
+    }
+
+    #[test]
+    fn test_endpoints() {
+        assert!(!Config::default().metrics.enabled);
+        let config: Config =
+            toml::from_str("[metrics]\nenabled = true\nlisten = \"0.0.0.0:9000\"").unwrap();
+        assert!(config.metrics.enabled);
+        assert_eq!(
+            config.metrics.listen,
+            SocketAddr::from(([0, 0, 0, 0], 9000))
+        );
+        assert!(toml::from_str::<Config>("[metrics]\nlisten = \"localhost\"").is_err());
+
+        assert!(!config.health.enabled);
+        assert_eq!(
+            config.health.listen,
+            SocketAddr::from(([127, 0, 0, 1], 9185))
+        );
+        assert_eq!(config.health.gateway_grace, 300);
+    }
+
+    #[test]
+    fn test_guild_overrides() {
+        let config: Config = toml::from_str(
+            r#"
+prefix = "?"
+
+[cooldown.guild]
+period = 1.5
+
+[guilds.42]
+admin_roles = [7, 8]
+prefix = "$"
+
+[guilds.42.random]
+weighted = true
+
+[guilds.42.playback]
+max_seconds = 0
+
+[guilds.1]
+transcripts = true
+now_playing = true
+
+[guilds.42.cooldown.channel]
+period = 10
+burst = 4
+"#,
+        )
+        .unwrap();
+
+        // Other guilds get the top-level settings.
+        let other = config.cooldowns(GuildId::new(1));
+        assert_eq!(
+            other.guild,
+            Some(Limit {
+                period: 1.5,
+                burst: 1
+            })
+        );
+        assert!(other.user.is_some());
+        assert_eq!(config.prefix(GuildId::new(1)), "?");
+        assert_eq!(config.prefix(GuildId::new(42)), "$");
+
+        let guild_id = GuildId::new(42);
+        let cooldowns = config.cooldowns(guild_id);
+        assert_eq!(
+            cooldowns.channel,
+            Some(Limit {
+                period: 10.0,
+                burst: 4
+            })
+        );
+        assert!(cooldowns.guild.is_none());
+
+        assert_eq!(config.random(GuildId::new(1)), RandomConfig::default());
+        assert_eq!(
+            config.random(guild_id),
+            RandomConfig {
+                shuffle: true,
+                weighted: true,
+                broken_after: 3
+            }
+        );
+
+        assert_eq!(config.playback(GuildId::new(1)).limit(), Some(30.0));
+        assert_eq!(config.playback(guild_id).limit(), None);
+        assert!(config.transcripts(GuildId::new(1)));
+        assert!(!config.transcripts(guild_id));
+        assert!(config.now_playing(GuildId::new(1)));
+        assert!(!config.now_playing(guild_id));
+
+        assert!(config.is_admin(guild_id, &[RoleId::new(3), RoleId::new(8)]));
+        assert!(!config.is_admin(guild_id, &[RoleId::new(3)]));
+        assert!(!config.is_admin(GuildId::new(1), &[RoleId::new(8)]));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::config::{Cooldowns, Limit};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// What a cooldown applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    User,
+    Channel,
+    Guild,
+}
+
+/// Once this many buckets are being tracked, forget about the ones that
+/// have fully refilled so the map doesn't grow forever.
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// Top up the bucket for the time elapsed since it was last touched.
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed / limit.period).min(limit.burst as f64);
+        self.last = now;
+    }
+}
+
+/// Token bucket rate limiter for quip playback. Buckets are keyed by scope,
+/// guild and the ID of the user/channel/guild so per-guild settings for the
+/// same user don't interfere with each other.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(Scope, u64, u64), Bucket>>,
+}
+
+impl RateLimiter {
+    /// Try to take a play from every applicable bucket. If any of them is
+    /// empty, nothing is consumed and the time until a play is available
+    /// again is returned instead.
+    pub fn check(
+        &self,
+        cooldowns: &Cooldowns,
+        guild: u64,
+        channel: u64,
+        user: u64,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let scopes = [
+            (Scope::User, user, cooldowns.user),
+            (Scope::Channel, channel, cooldowns.channel),
+            (Scope::Guild, guild, cooldowns.guild),
+        ];
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // First pass: refill and find the longest wait, if any.
+        let mut wait = Duration::ZERO;
+        for (scope, id, limit) in scopes.iter() {
+            let Some(limit) = limit.filter(|l| l.period > 0.0 && l.burst > 0) else {
+                continue;
+            };
+            let bucket = buckets.entry((*scope, guild, *id)).or_insert(Bucket {
+                tokens: limit.burst as f64,
+                last: now,
+            });
+            bucket.refill(&limit, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64(
+                    (1.0 - bucket.tokens) * limit.period,
+                ));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        // Second pass: everything has room, so consume.
+        for (scope, id, limit) in scopes.iter() {
+            if limit.is_some()
+                && let Some(bucket) = buckets.get_mut(&(*scope, guild, *id))
+            {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|(scope, _, _), bucket| {
+                let limit = match scope {
+                    Scope::User => cooldowns.user,
+                    Scope::Channel => cooldowns.channel,
+                    Scope::Guild => cooldowns.guild,
+                };
+                // Buckets from other guilds may have other limits, but
+                // dropping a bucket only ever errs in the user's favor.
+                limit.is_some_and(|l| {
+                    bucket.tokens
+                        + now.saturating_duration_since(bucket.last).as_secs_f64() / l.period
+                        < l.burst as f64
+                })
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_only(period: f64, burst: u32) -> Cooldowns {
+        Cooldowns {
+            user: Some(Limit { period, burst }),
+            channel: None,
+            guild: None,
+        }
+    }
+
+    #[test]
+    fn test_burst_then_wait() {
+        let limiter = RateLimiter::default();
+        let cooldowns = user_only(2.0, 3);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check(&cooldowns, 1, 2, 3, now).is_ok());
+        }
+        let wait = limiter.check(&cooldowns, 1, 2, 3, now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(2));
+
+        // Half a period later, still half a period to go.
+        let later = now + Duration::from_secs(1);
+        let wait = limiter.check(&cooldowns, 1, 2, 3, later).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+
+        // A full period later, one more play is allowed, but only one.
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.check(&cooldowns, 1, 2, 3, later).is_ok());
+        assert!(limiter.check(&cooldowns, 1, 2, 3, later).is_err());
+    }
+
+    #[test]
+    fn test_users_and_guilds_are_independent() {
+        let limiter = RateLimiter::default();
+        let cooldowns = user_only(10.0, 1);
+        let now = Instant::now();
+
+        assert!(limiter.check(&cooldowns, 1, 2, 3, now).is_ok());
+        assert!(limiter.check(&cooldowns, 1, 2, 3, now).is_err());
+        // Another user in the same guild.
+        assert!(limiter.check(&cooldowns, 1, 2, 4, now).is_ok());
+        // Same user in another guild.
+        assert!(limiter.check(&cooldowns, 5, 6, 3, now).is_ok());
+    }
+
+    #[test]
+    fn test_failed_check_consumes_nothing() {
+        let limiter = RateLimiter::default();
+        let cooldowns = Cooldowns {
+            user: Some(Limit {
+                period: 1.0,
+                burst: 2,
+            }),
+            channel: None,
+            guild: Some(Limit {
+                period: 10.0,
+                burst: 1,
+            }),
+        };
+        let now = Instant::now();
+
+        assert!(limiter.check(&cooldowns, 1, 2, 3, now).is_ok());
+        // The guild bucket is empty, so the user bucket must not be touched.
+        let wait = limiter.check(&cooldowns, 1, 2, 3, now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(10));
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.check(&cooldowns, 1, 2, 3, later).is_ok());
+        assert!(limiter.check(&cooldowns, 1, 2, 3, later).is_err());
+    }
+
+    #[test]
+    fn test_disabled_limit() {
+        let limiter = RateLimiter::default();
+        let cooldowns = user_only(0.0, 1);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check(&cooldowns, 1, 2, 3, now).is_ok());
+        }
+    }
+}
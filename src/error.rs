@@ -0,0 +1,177 @@
+use poise::serenity_prelude as serenity;
+use std::fmt;
+
+/// Everything that can go wrong handling a command or event. The variant
+/// decides how on_error reports it: mistakes in what was asked for are only
+/// explained to the user, everything else is logged too.
+#[derive(Debug)]
+pub enum Error {
+    /// Something that can't be done as asked, e.g. a bad argument. The
+    /// message is meant for the user as is.
+    Input(String),
+    /// An unknown category, or no quip in `category` matching what was
+    /// asked for. The reply adds how to list what there is, which depends on
+    /// the guild's prefix.
+    NotFound {
+        message: String,
+        category: Option<String>,
+    },
+    /// The quip library couldn't be read or changed, e.g. a file that has
+    /// gone missing since the last scan.
+    Library(String),
+    /// Joining, leaving or playing in a voice channel failed.
+    Voice(String),
+    /// Anything else, like database or Discord API failures. The details are
+    /// only logged; users get an apology.
+    Internal(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// What to tell the user who ran into this, given the command prefix in
+    /// effect where they did.
+    pub fn reply(&self, prefix: &str) -> String {
+        match self {
+            Error::Input(message) | Error::Library(message) | Error::Voice(message) => {
+                message.clone()
+            }
+            Error::NotFound {
+                message,
+                category: None,
+            } => format!(
+                "{} Use \"{}list\" with no arguments to get valid categories.",
+                message, prefix
+            ),
+            Error::NotFound {
+                message,
+                category: Some(category),
+            } => format!(
+                "{} Use \"{}list {}\" to see available quips.",
+                message, prefix, category
+            ),
+            Error::Internal(_) => {
+                String::from("Something went wrong on my end, sorry! It has been logged.")
+            }
+        }
+    }
+
+    /// Whether this is worth logging, rather than a plain user mistake.
+    pub fn should_log(&self) -> bool {
+        !matches!(self, Error::Input(_) | Error::NotFound { .. })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Input(message)
+            | Error::NotFound { message, .. }
+            | Error::Library(message)
+            | Error::Voice(message) => f.write_str(message),
+            Error::Internal(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Internal(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// Plain messages are for explaining what can't be done as asked. Failures
+// worth logging should pick their variant where they happen.
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Input(message.to_string())
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Input(message)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Library(format!("Couldn't access the quip files: {}", error))
+    }
+}
+
+impl From<songbird::error::JoinError> for Error {
+    fn from(error: songbird::error::JoinError) -> Self {
+        Error::Voice(format!("Couldn't join the voice channel: {}", error))
+    }
+}
+
+impl From<songbird::error::ControlError> for Error {
+    fn from(error: songbird::error::ControlError) -> Self {
+        Error::Voice(format!("Couldn't control playback: {}", error))
+    }
+}
+
+impl From<serenity::Error> for Error {
+    fn from(error: serenity::Error) -> Self {
+        Error::Internal(Box::new(error))
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Error::Internal(Box::new(error))
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(error: tokio::task::JoinError) -> Self {
+        Error::Internal(Box::new(error))
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Internal(Box::new(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply() {
+        let input = Error::from("Unknown category `a4`.");
+        assert_eq!(input.reply("!"), "Unknown category `a4`.");
+        assert!(!input.should_log());
+
+        let not_found = Error::NotFound {
+            message: String::from("No quip in category `a1` matches `x`."),
+            category: Some(String::from("a1")),
+        };
+        assert_eq!(
+            not_found.reply("?"),
+            "No quip in category `a1` matches `x`. Use \"?list a1\" to see available quips."
+        );
+        assert_eq!(
+            not_found.to_string(),
+            "No quip in category `a1` matches `x`."
+        );
+        assert!(!not_found.should_log());
+
+        let library = Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(
+            library
+                .reply("!")
+                .starts_with("Couldn't access the quip files: ")
+        );
+        assert!(library.should_log());
+
+        let internal = Error::Internal("database is locked".into());
+        assert_eq!(internal.to_string(), "database is locked");
+        assert!(!internal.reply("!").contains("database"));
+        assert!(internal.should_log());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::http::Response;
+use poise::serenity_prelude as serenity;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How often the gateway is looked at between requests, see watch_gateway.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the bot is up to, for the health endpoint. It's shared with the
+/// endpoint before anything else starts, so it can tell "still starting" from
+/// "stuck" while the library is scanned and the gateway connects.
+pub struct Status {
+    started: Instant,
+    library_loaded: AtomicBool,
+    client: OnceLock<Client>,
+    // How long the gateway may be down before the bot counts as wedged.
+    grace: Duration,
+    // Since when the gateway has been down, if it is.
+    disconnected_since: Mutex<Option<Instant>>,
+}
+
+/// The parts of the Discord client the endpoint looks at.
+struct Client {
+    shards: Arc<serenity::ShardManager>,
+    cache: Arc<serenity::Cache>,
+}
+
+impl Status {
+    pub fn new(grace: Duration) -> Status {
+        Status {
+            started: Instant::now(),
+            library_loaded: AtomicBool::new(false),
+            client: OnceLock::new(),
+            grace,
+            disconnected_since: Mutex::new(None),
+        }
+    }
+
+    /// The library has been scanned.
+    pub fn library_loaded(&self) {
+        self.library_loaded.store(true, Ordering::Relaxed);
+    }
+
+    /// The Discord client has been built, so there's a gateway to look at.
+    pub fn client_built(&self, shards: Arc<serenity::ShardManager>, cache: Arc<serenity::Cache>) {
+        let _ = self.client.set(Client { shards, cache });
+    }
+
+    /// Keep track of when the gateway goes down, so that liveness doesn't
+    /// depend on how often it's asked for. Runs until the bot exits.
+    pub async fn watch_gateway(&self) {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Some((connected, _)) = self.gateway().await {
+                self.disconnected_for(connected);
+            }
+        }
+    }
+
+    /// Whether all shards are connected, and the number of guilds. None
+    /// until the client has been built.
+    async fn gateway(&self) -> Option<(bool, usize)> {
+        let client = self.client.get()?;
+        let runners = client.shards.runners.lock().await;
+        let connected = !runners.is_empty()
+            && runners
+                .values()
+                .all(|r| r.stage == serenity::ConnectionStage::Connected);
+        Some((connected, client.cache.guild_count()))
+    }
+
+    /// Note whether the gateway is connected, returning how long it has been
+    /// down.
+    fn disconnected_for(&self, connected: bool) -> Duration {
+        let mut since = self
+            .disconnected_since
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if connected {
+            *since = None;
+            return Duration::ZERO;
+        }
+        since.get_or_insert_with(Instant::now).elapsed()
+    }
+
+    /// Take stock. Voice sessions are counted by the caller, which has the
+    /// voice manager.
+    pub async fn report(&self, voice_sessions: usize) -> Report {
+        let (gateway_connected, guilds, disconnected) = match self.gateway().await {
+            Some((connected, guilds)) => (connected, guilds, self.disconnected_for(connected)),
+            // Still starting up, which readiness covers.
+            None => (false, 0, Duration::ZERO),
+        };
+        Report {
+            live: disconnected <= self.grace,
+            gateway_connected,
+            library_loaded: self.library_loaded.load(Ordering::Relaxed),
+            uptime: self.started.elapsed(),
+            guilds,
+            voice_sessions,
+        }
+    }
+}
+
+/// The state of the bot at one point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// False once the gateway has been down for longer than it takes to
+    /// reconnect, meaning the bot is wedged and should be restarted.
+    pub live: bool,
+    pub gateway_connected: bool,
+    pub library_loaded: bool,
+    pub uptime: Duration,
+    pub guilds: usize,
+    pub voice_sessions: usize,
+}
+
+impl Report {
+    /// Whether the bot can take commands.
+    pub fn ready(&self) -> bool {
+        self.gateway_connected && self.library_loaded
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"live\":{},\"ready\":{},\"gateway_connected\":{},\"library_loaded\":{},\"uptime_seconds\":{},\"guilds\":{},\"voice_sessions\":{}}}\n",
+            self.live,
+            self.ready(),
+            self.gateway_connected,
+            self.library_loaded,
+            self.uptime.as_secs(),
+            self.guilds,
+            self.voice_sessions
+        )
+    }
+}
+
+/// Answer a request to the health endpoint. "/health/live" is fine unless the
+/// gateway has been down for too long; "/health/ready" only once the bot can
+/// take commands. Both describe the bot in JSON.
+pub fn respond(path: &str, report: &Report) -> Response {
+    let status = match path {
+        "/health/live" if report.live => 200,
+        "/health/live" => 503,
+        "/health/ready" if report.ready() => 200,
+        "/health/ready" => 503,
+        _ => return Response::not_found(),
+    };
+    Response {
+        status,
+        content_type: "application/json",
+        body: report.to_json(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_respond() {
+        let mut report = Report {
+            live: true,
+            gateway_connected: false,
+            library_loaded: true,
+            uptime: Duration::from_secs(90),
+            guilds: 2,
+            voice_sessions: 1,
+        };
+        assert_eq!(respond("/health/live", &report).status, 200);
+        assert_eq!(respond("/health/ready", &report).status, 503);
+        assert_eq!(respond("/metrics", &report).status, 404);
+
+        report.gateway_connected = true;
+        let response = respond("/health/ready", &report);
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.body,
+            "{\"live\":true,\"ready\":true,\"gateway_connected\":true,\"library_loaded\":true,\"uptime_seconds\":90,\"guilds\":2,\"voice_sessions\":1}\n"
+        );
+
+        report.live = false;
+        assert_eq!(respond("/health/live", &report).status, 503);
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let status = Status::new(Duration::from_secs(60));
+        let report = status.report(0).await;
+        assert!(!report.gateway_connected && !report.library_loaded);
+        assert!(report.live);
+        status.library_loaded();
+        assert!(status.report(0).await.library_loaded);
+    }
+
+    #[test]
+    fn test_disconnected_for() {
+        let status = Status::new(Duration::ZERO);
+        assert_eq!(status.disconnected_for(true), Duration::ZERO);
+        let first = status.disconnected_for(false);
+        std::thread::sleep(Duration::from_millis(10));
+        // Counted from when it first went down.
+        assert!(status.disconnected_for(false) >= first + Duration::from_millis(10));
+        assert_eq!(status.disconnected_for(true), Duration::ZERO);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::QuipId;
+use poise::serenity_prelude::{GuildId, UserId};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, PoisonError};
+
+/// How many recent plays are remembered per guild.
+pub const HISTORY_LEN: usize = 25;
+
+/// A recently played quip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub quip: QuipId,
+    pub user_id: UserId,
+    /// Seconds since the Unix epoch.
+    pub played_at: i64,
+}
+
+/// Ring buffer of recent plays for each guild, for "!again" and "!history".
+/// This only lives in memory: the full record of plays is in the store.
+#[derive(Debug, Default)]
+pub struct History {
+    guilds: Mutex<HashMap<GuildId, VecDeque<Entry>>>,
+}
+
+impl History {
+    pub fn push(&self, guild_id: GuildId, entry: Entry) {
+        let mut guilds = self.guilds.lock().unwrap_or_else(PoisonError::into_inner);
+        let ring = guilds
+            .entry(guild_id)
+            .or_insert_with(|| VecDeque::with_capacity(HISTORY_LEN));
+        if ring.len() == HISTORY_LEN {
+            ring.pop_back();
+        }
+        ring.push_front(entry);
+    }
+
+    /// Get the nth most recent play, where 1 is the latest.
+    pub fn get(&self, guild_id: GuildId, n: usize) -> Option<Entry> {
+        let guilds = self.guilds.lock().unwrap_or_else(PoisonError::into_inner);
+        guilds.get(&guild_id)?.get(n.checked_sub(1)?).cloned()
+    }
+
+    /// The most recent plays, latest first.
+    pub fn recent(&self, guild_id: GuildId, n: usize) -> Vec<Entry> {
+        let guilds = self.guilds.lock().unwrap_or_else(PoisonError::into_inner);
+        match guilds.get(&guild_id) {
+            Some(ring) => ring.iter().take(n).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Point entries for a moved or renamed quip at its new name, in every
+    /// guild.
+    pub fn rename(&self, from: &QuipId, to: &QuipId) {
+        let mut guilds = self.guilds.lock().unwrap_or_else(PoisonError::into_inner);
+        for entry in guilds.values_mut().flatten() {
+            if entry.quip == *from {
+                entry.quip = to.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: usize) -> Entry {
+        Entry {
+            quip: ("a3".to_string(), format!("{}.mp3", n)),
+            user_id: UserId::new(1),
+            played_at: n as i64,
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let history = History::default();
+        let guild_id = GuildId::new(1);
+        assert_eq!(history.get(guild_id, 1), None);
+        assert!(history.recent(guild_id, 5).is_empty());
+
+        for n in 1..=3 {
+            history.push(guild_id, entry(n));
+        }
+        assert_eq!(history.get(guild_id, 1), Some(entry(3)));
+        assert_eq!(history.get(guild_id, 3), Some(entry(1)));
+        assert_eq!(history.get(guild_id, 4), None);
+        assert_eq!(history.get(guild_id, 0), None);
+        assert_eq!(history.recent(guild_id, 2), vec![entry(3), entry(2)]);
+
+        // Other guilds are separate.
+        assert_eq!(history.get(GuildId::new(2), 1), None);
+    }
+
+    #[test]
+    fn test_history_wraps() {
+        let history = History::default();
+        let guild_id = GuildId::new(1);
+        for n in 0..HISTORY_LEN + 5 {
+            history.push(guild_id, entry(n));
+        }
+        let recent = history.recent(guild_id, usize::MAX);
+        assert_eq!(recent.len(), HISTORY_LEN);
+        assert_eq!(recent[0], entry(HISTORY_LEN + 4));
+        assert_eq!(recent[HISTORY_LEN - 1], entry(5));
+    }
+
+    #[test]
+    fn test_history_rename() {
+        let history = History::default();
+        for guild in 1..=2 {
+            for n in 1..=2 {
+                history.push(GuildId::new(guild), entry(n));
+            }
+        }
+        let to = ("a1".to_string(), "moved.mp3".to_string());
+        history.rename(&entry(1).quip, &to);
+        for guild in 1..=2 {
+            let guild_id = GuildId::new(guild);
+            assert_eq!(history.get(guild_id, 1), Some(entry(2)));
+            assert_eq!(history.get(guild_id, 2).unwrap().quip, to);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How long a client gets to send its request before it's hung up on.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request line or header line read, so a client can't make the bot
+/// buffer an endless line.
+const MAX_LINE: usize = 8 * 1024;
+
+/// An answer to a request.
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: String) -> Response {
+        Response {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response::text(404, "Not found.\n")
+    }
+
+    /// A plain text response with the given status.
+    pub fn text(status: u16, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.to_string(),
+        }
+    }
+}
+
+/// Listen on the given address, e.g. "127.0.0.1:9184".
+pub async fn bind(address: SocketAddr) -> Result<TcpListener, Error> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|e| Error::Internal(format!("Can't listen on {}: {}", address, e).into()))
+}
+
+/// Answer HTTP requests until the bot exits, calling `handle` with the path of
+/// each GET request. This is just enough HTTP/1.1 for monitoring tools on the
+/// same host: one request per connection, and no request bodies.
+pub async fn serve<F, Fut>(listener: TcpListener, handle: F)
+where
+    F: Fn(String) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to accept HTTP connection");
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, handle).await {
+                tracing::debug!(%peer, error = %e, "Failed to answer HTTP request");
+            }
+        });
+    }
+}
+
+/// Read one request from the stream and answer it.
+async fn respond<F, Fut>(mut stream: TcpStream, handle: F) -> std::io::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let response = match parse_request_line(&request) {
+        Some(("GET", path)) => handle(path.to_string()).await,
+        Some(_) => Response::text(405, "Only GET is supported.\n"),
+        None => Response::text(400, "Bad request.\n"),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read the request line, skipping the headers after it.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE as u64)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 || line.trim_end().is_empty() {
+            return Ok(request);
+        }
+        if request.is_empty() {
+            request = line.clone();
+        }
+    }
+}
+
+/// The method and path of a request line, e.g. ("GET", "/metrics") for
+/// "GET /metrics?x=1 HTTP/1.1".
+fn parse_request_line(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    Some((method, target.split('?').next()?))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_line() {
+        assert_eq!(
+            parse_request_line("GET /metrics HTTP/1.1\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(
+            parse_request_line("GET /health?verbose=1 HTTP/1.0\r\n"),
+            Some(("GET", "/health"))
+        );
+        assert_eq!(parse_request_line("GET /metrics\r\n"), None);
+        assert_eq!(parse_request_line(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, |path| async move {
+            match path.as_str() {
+                "/hello" => Response::ok("text/plain", String::from("hi")),
+                _ => Response::not_found(),
+            }
+        }));
+
+        let get = |request: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("GET /hello HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+        let response = get("GET /nope HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get("POST /hello HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 "));
+    }
+}
@@ -0,0 +1,813 @@
+use crate::audio::{self, AudioInfo};
+use crate::config::{Config, Order};
+use crate::shuffle::Filter;
+use crate::store::Store;
+use crate::suggest::{closest, did_you_mean};
+use crate::tags::{self, TagMap};
+use crate::{Error, QuipId};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{DirEntry, read_dir};
+use std::path::{Path, PathBuf};
+
+pub type FileMap = HashMap<String, Vec<DirEntry>>;
+
+/// Hidden directory in the audio directory where deleted quips go, organized
+/// by category. Hidden directories aren't scanned for quips, so moving a file
+/// back out of here restores it.
+pub const TRASH_DIR: &str = ".trash";
+
+/// A snapshot of the audio directory. The bot swaps in a fresh one whenever
+/// the library changes on disk (see Data::reload), so lookups never see a half
+/// updated library.
+pub struct Library {
+    // Directory the library was read from.
+    pub top_dir: PathBuf,
+    // Map of quip categories to directory entries.
+    pub file_map: FileMap,
+    // Tags from the sidecar file in the audio directory. Tags added with
+    // "!tag" live in the store instead.
+    pub sidecar_tags: TagMap,
+    // Whether categories are matched ignoring case, like command names.
+    pub case_insensitive: bool,
+    // Details of each quip's file, for "!info".
+    pub info: HashMap<QuipId, AudioInfo>,
+    // Files in category directories that aren't in a supported format.
+    pub skipped: Vec<PathBuf>,
+    // How many quips' details were reused from the previous scan rather than
+    // analyzed again.
+    pub reused: usize,
+}
+
+impl Library {
+    /// Read every category (subdirectory) of the given directory. Decoding
+    /// every file is slow, so files that haven't changed since the previous
+    /// scan, if any, aren't analyzed again.
+    pub fn scan(
+        top_dir: &Path,
+        config: &Config,
+        previous: Option<&Library>,
+    ) -> Result<Library, Error> {
+        let sidecar_tags = tags::load_sidecar(&top_dir.join(tags::SIDECAR))?;
+
+        // Initialize the file map.
+        let mut file_map: HashMap<String, Vec<DirEntry>> = HashMap::new();
+        let mut skipped = Vec::new();
+
+        // Loop over directories within the top_dir and fill out the HashMap.
+        let result = read_dir(top_dir)?;
+        for r in result {
+            let u = r?;
+            // Only work with directories, skipping hidden ones like the
+            // moderation queue.
+            if u.file_type()?.is_dir() && !u.file_name().to_string_lossy().starts_with('.') {
+                // Iterate over the files and place in the HashMap using the
+                // directory's name as a key.
+                let key = u.file_name().to_string_lossy().into_owned();
+                let files = match read_dir(u.path()) {
+                    Ok(files) => files,
+                    Err(e) => {
+                        tracing::warn!(category = %key, error = %e, "Can't read category");
+                        continue;
+                    }
+                };
+                for f in files {
+                    // One unreadable file shouldn't keep the rest from loading.
+                    let _f = match f.and_then(|f| f.file_type().map(|t| (f, t))) {
+                        Ok((f, t)) if t.is_file() => f,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            tracing::warn!(category = %key, error = %e, "Can't read quip file");
+                            continue;
+                        }
+                    };
+                    // Files the bot can't play would only fail later.
+                    if !audio::is_supported(&_f.path()) {
+                        skipped.push(_f.path());
+                        continue;
+                    }
+                    match file_map.entry(key.to_owned()) {
+                        std::collections::hash_map::Entry::Occupied(mut oe) => {
+                            oe.get_mut().push(_f);
+                        }
+                        std::collections::hash_map::Entry::Vacant(ve) => {
+                            ve.insert(vec![_f]);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut info = HashMap::new();
+        let mut reused = 0;
+        for dir_entry in file_map.values().flatten() {
+            let id = quip_id(dir_entry);
+            let quip_info = match dir_entry.metadata() {
+                Ok(metadata) => {
+                    let unchanged = previous.and_then(|p| p.info.get(&id)).filter(|i| {
+                        i.size == metadata.len() && i.modified == audio::modified(&metadata)
+                    });
+                    match unchanged {
+                        Some(quip_info) => {
+                            reused += 1;
+                            quip_info.clone()
+                        }
+                        None => AudioInfo::read(&dir_entry.path()),
+                    }
+                }
+                Err(e) => AudioInfo::unreadable(&e),
+            };
+            if let Err(reason) = &quip_info.analysis {
+                tracing::warn!(category = %id.0, file_name = %id.1, %reason, "Can't play quip");
+            }
+            info.insert(id, quip_info);
+        }
+
+        // Quip numbers are positions in these lists.
+        for (category, files) in file_map.iter_mut() {
+            sort_quips(files, config.order(category), &info);
+        }
+
+        skipped.sort();
+        if !skipped.is_empty() {
+            let names: Vec<String> = skipped
+                .iter()
+                .map(|p| p.strip_prefix(top_dir).unwrap_or(p).display().to_string())
+                .collect();
+            tracing::warn!(
+                count = skipped.len(),
+                supported = %audio::EXTENSIONS.join(", "),
+                files = %names.join(", "),
+                "Skipped files in unsupported formats"
+            );
+        }
+
+        // Tags for files that don't exist are most likely typos.
+        for (category, file_name) in sidecar_tags.keys() {
+            let exists = file_map.get(category).is_some_and(|files| {
+                files
+                    .iter()
+                    .any(|f| f.file_name().to_string_lossy() == *file_name)
+            });
+            if !exists {
+                tracing::warn!(
+                    sidecar = tags::SIDECAR,
+                    category,
+                    file_name,
+                    "Tags for unknown quip"
+                );
+            }
+        }
+        Ok(Library {
+            top_dir: top_dir.to_path_buf(),
+            file_map,
+            sidecar_tags,
+            case_insensitive: config.case_insensitive_commands,
+            info,
+            skipped,
+            reused,
+        })
+    }
+
+    /// Number of quips in each format (file extension), e.g. "120 mp3, 4 opus",
+    /// plus how many of them are streamed without re-encoding.
+    pub fn describe_formats(&self) -> String {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for dir_entry in self.file_map.values().flatten() {
+            *counts
+                .entry(audio::extension(&dir_entry.path()))
+                .or_default() += 1;
+        }
+        let mut formats: Vec<String> = counts
+            .iter()
+            .map(|(format, count)| format!("{} {}", count, format))
+            .collect();
+        let passthrough = self
+            .info
+            .values()
+            .filter(|i| i.analysis.as_ref().is_ok_and(|a| a.passthrough))
+            .count();
+        if passthrough > 0 {
+            formats.push(format!("{} streamed without re-encoding", passthrough));
+        }
+        formats.join(", ")
+    }
+
+    /// Resolve a category name to the key used in the file_map. If there's no
+    /// such category, return an error which eventually gets floated up to the
+    /// user, suggesting the closest matching categories if any.
+    pub fn resolve_category(&self, cat: &str) -> Result<&String, Error> {
+        if let Some((key, _)) = self.file_map.get_key_value(cat) {
+            return Ok(key);
+        };
+        // Command names are matched case-insensitively by default, so do the
+        // same for categories if there's no exact match.
+        if self.case_insensitive
+            && let Some(key) = self
+                .file_map
+                .keys()
+                .find(|k| k.to_lowercase() == cat.to_lowercase())
+        {
+            return Ok(key);
+        }
+
+        let mut keys: Vec<&str> = self.file_map.keys().map(|k| k.as_str()).collect();
+        keys.sort();
+        Err(Error::NotFound {
+            message: format!(
+                "Unknown category `{}`{}.",
+                cat,
+                did_you_mean(&closest(cat, keys))
+            ),
+            category: None,
+        })
+    }
+
+    /// Get a vector from the file_map from the given key ("cat" for "category").
+    pub fn get_vec(&self, cat: &str) -> Result<&Vec<DirEntry>, Error> {
+        Ok(&self.file_map[self.resolve_category(cat)?])
+    }
+
+    /// Get every quip a random pick may choose from, as (index within its
+    /// category, DirEntry), in a stable order. Categories in the filter are
+    /// checked and normalized first, so typos get suggestions rather than
+    /// silently matching nothing.
+    pub fn random_pool(
+        &self,
+        mut filter: Filter,
+        store: &dyn Store,
+    ) -> Result<Vec<(usize, &DirEntry)>, Error> {
+        for cat in filter.include.iter_mut().chain(filter.exclude.iter_mut()) {
+            *cat = self.resolve_category(cat)?.clone();
+        }
+        // Only look tags up when they're asked for.
+        let tags = if filter.has_tags() {
+            self.tags(store)?
+        } else {
+            TagMap::new()
+        };
+        let failures = match filter.broken_after {
+            Some(n) if n > 0 => store.failure_counts()?,
+            _ => HashMap::new(),
+        };
+        let broken = |id: &QuipId| {
+            filter
+                .broken_after
+                .is_some_and(|n| failures.get(id).is_some_and(|&f| f >= u64::from(n)))
+        };
+        let mut keys: Vec<&String> = self.file_map.keys().filter(|k| filter.allows(k)).collect();
+        keys.sort();
+        Ok(keys
+            .into_iter()
+            .flat_map(|k| self.file_map[k].iter().enumerate())
+            .filter(|(_, dir_entry)| {
+                let id = quip_id(dir_entry);
+                filter.allows_tags(tags.get(&id))
+                    && !self.is_long(&id, filter.max_seconds)
+                    && !broken(&id)
+            })
+            .collect())
+    }
+
+    /// Duration of a quip in seconds, if it could be decoded.
+    pub fn seconds(&self, id: &QuipId) -> Option<f64> {
+        let info = self.info.get(id)?;
+        info.analysis.as_ref().ok().map(|a| a.seconds)
+    }
+
+    /// Whether a quip is longer than the given limit. There's no telling for
+    /// quips that couldn't be decoded, so those aren't.
+    pub fn is_long(&self, id: &QuipId, limit: Option<f64>) -> bool {
+        limit.is_some_and(|limit| self.seconds(id).is_some_and(|s| s > limit))
+    }
+
+    /// Every quip longer than the given limit, sorted.
+    pub fn long_quips(&self, limit: f64) -> Vec<&QuipId> {
+        let mut long: Vec<&QuipId> = self
+            .info
+            .keys()
+            .filter(|id| self.is_long(id, Some(limit)))
+            .collect();
+        long.sort();
+        long
+    }
+
+    /// All quip tags, from both the sidecar file and the store.
+    pub fn tags(&self, store: &dyn Store) -> Result<TagMap, Error> {
+        let mut tags = store.tags()?;
+        tags::merge(&mut tags, &self.sidecar_tags);
+        Ok(tags)
+    }
+
+    /// Find a quip within a category, either by its 1-based number (as shown by
+    /// "!list <category>") or by (part of) its file name. Returns the 0-based
+    /// index within the category along with the DirEntry.
+    pub fn find_quip(&self, cat: &String, query: &str) -> Result<(usize, &DirEntry), Error> {
+        let file_vec = self.get_vec(cat)?;
+        let query = query.trim();
+
+        if let Ok(num) = query.parse::<usize>() {
+            return match num.checked_sub(1).and_then(|idx| file_vec.get(idx)) {
+                Some(dir_entry) => Ok((num - 1, dir_entry)),
+                None => Err(format!(
+                    "The given integer \"{}\" is invalid. Valid integers for the {:?} command range from 1 to {}",
+                    num,
+                    cat,
+                    file_vec.len()
+                )
+                .into()),
+            };
+        }
+
+        // Match by name: case-insensitive and ignoring the file extension.
+        let stems: Vec<String> = file_vec.iter().map(quip_stem).collect();
+        let needle = query.to_lowercase();
+        if let Some(idx) = stems.iter().position(|s| s.to_lowercase() == needle) {
+            return Ok((idx, &file_vec[idx]));
+        }
+        let matches: Vec<usize> = stems
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.to_lowercase().contains(&needle))
+            .map(|(idx, _)| idx)
+            .collect();
+        match matches.as_slice() {
+            [idx] => Ok((*idx, &file_vec[*idx])),
+            [] => Err(Error::NotFound {
+                message: format!(
+                    "No quip in category `{}` matches `{}`{}.",
+                    cat,
+                    query,
+                    did_you_mean(&closest(query, stems.iter().map(|s| s.as_str())))
+                ),
+                category: Some(cat.clone()),
+            }),
+            _ => {
+                let names: Vec<String> = matches
+                    .iter()
+                    .map(|idx| format!("{}: {}", idx + 1, stems[*idx]))
+                    .collect();
+                Err(format!(
+                    "`{}` matches several quips in category `{}`, please be more specific:\n```\n{}\n```",
+                    query,
+                    cat,
+                    names.join("\n")
+                )
+                .into())
+            }
+        }
+    }
+
+    /// Look up a quip by its stable identity (see quip_id), e.g. to resolve
+    /// favourites after quips have been added or reordered.
+    pub fn find_by_id(&self, id: &QuipId) -> Option<(usize, &DirEntry)> {
+        self.file_map
+            .get(&id.0)?
+            .iter()
+            .enumerate()
+            .find(|(_, dir_entry)| dir_entry.file_name().to_string_lossy() == id.1)
+    }
+
+    /// Whether a category has a quip with the given name, ignoring case and
+    /// extension. Quips are played by name, so such a name is taken.
+    pub fn has_stem(&self, category: &str, stem: &str) -> bool {
+        self.file_map.get(category).is_some_and(|files| {
+            files
+                .iter()
+                .any(|f| quip_stem(f).to_lowercase() == stem.to_lowercase())
+        })
+    }
+
+    /// Rename a quip and/or move it to another existing category. The caller
+    /// is responsible for reloading the library afterwards.
+    pub fn move_quip(&self, from: &QuipId, to: &QuipId) -> Result<(), Error> {
+        if !self.file_map.contains_key(&to.0) {
+            return Err(format!("Unknown category `{}`.", to.0).into());
+        }
+        let stem = Path::new(&to.1)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        // Changing only the case of a name within a category is fine.
+        let same_quip = from.0 == to.0 && from.1.to_lowercase() == to.1.to_lowercase();
+        if !same_quip && self.has_stem(&to.0, &stem) {
+            return Err(
+                format!("Category `{}` already has a quip named \"{}\".", to.0, stem).into(),
+            );
+        }
+        std::fs::rename(
+            self.top_dir.join(&from.0).join(&from.1),
+            self.top_dir.join(&to.0).join(&to.1),
+        )?;
+        Ok(())
+    }
+
+    /// Move a quip to the trash, returning where it ended up. A quip deleted
+    /// before under the same name is kept by giving this one a timestamp, and
+    /// a counter if that's taken too. The caller is responsible for reloading
+    /// the library afterwards.
+    pub fn trash_quip(&self, id: &QuipId, now: i64) -> Result<PathBuf, Error> {
+        let trash = self.top_dir.join(TRASH_DIR).join(&id.0);
+        std::fs::create_dir_all(&trash)?;
+        let mut path = trash.join(&id.1);
+        // Renaming would overwrite, so find a name that's free.
+        let mut n = 1;
+        while path.exists() {
+            path = match n {
+                1 => trash.join(format!("{}-{}", now, id.1)),
+                _ => trash.join(format!("{}-{}-{}", now, n, id.1)),
+            };
+            n += 1;
+        }
+        std::fs::rename(self.top_dir.join(&id.0).join(&id.1), &path)?;
+        Ok(path)
+    }
+}
+
+/// The file name of a quip without its extension, used for play-by-name.
+pub fn quip_stem(dir_entry: &DirEntry) -> String {
+    dir_entry
+        .path()
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Sort the quips of a category into the given order. Ties, and files without
+/// a modification time or track number, fall back to natural order.
+fn sort_quips(files: &mut [DirEntry], order: Order, info: &HashMap<QuipId, AudioInfo>) {
+    let key = |dir_entry: &DirEntry| -> Option<i64> {
+        let quip_info = info.get(&quip_id(dir_entry))?;
+        match order {
+            Order::Natural => None,
+            Order::Modified => quip_info.modified,
+            Order::Metadata => quip_info
+                .analysis
+                .as_ref()
+                .ok()?
+                .track_number
+                .map(i64::from),
+        }
+    };
+    files.sort_by(|a, b| {
+        let (a_key, b_key) = (key(a), key(b));
+        b_key
+            .is_some()
+            .cmp(&a_key.is_some())
+            .then(a_key.cmp(&b_key))
+            .then_with(|| {
+                natural_cmp(
+                    &a.file_name().to_string_lossy(),
+                    &b.file_name().to_string_lossy(),
+                )
+            })
+    });
+}
+
+/// Compare file names the way people do: runs of digits are compared by
+/// value, so "2 Bar.mp3" comes before "10 Foo.mp3", and letters ignore case.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    while let (Some(&x), Some(&y)) = (a_chars.peek(), b_chars.peek()) {
+        let ordering = if x.is_ascii_digit() && y.is_ascii_digit() {
+            let x = take_digits(&mut a_chars);
+            let y = take_digits(&mut b_chars);
+            // Compare by value without parsing, which could overflow: fewer
+            // significant digits is smaller.
+            let (x_value, y_value) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+            x_value
+                .len()
+                .cmp(&y_value.len())
+                .then_with(|| x_value.cmp(y_value))
+        } else {
+            a_chars.next();
+            b_chars.next();
+            x.to_lowercase().cmp(y.to_lowercase())
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    // One is a prefix of the other, or they're equal but for case or leading
+    // zeros; either way, settle it so the order is always the same.
+    a_chars
+        .peek()
+        .is_some()
+        .cmp(&b_chars.peek().is_some())
+        .then_with(|| a.cmp(b))
+}
+
+fn take_digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        digits.push(c);
+    }
+    digits
+}
+
+/// Argument asking for a quip over the playback limit, see Library::is_long.
+pub const LONG_FLAG: &str = "--long";
+
+/// Remove "--long" from a command's arguments, returning the rest and whether
+/// it was there.
+pub fn take_long_flag(args: &str) -> (String, bool) {
+    let mut long = false;
+    let rest: Vec<&str> = args
+        .split_whitespace()
+        .filter(|arg| {
+            let is_flag = *arg == LONG_FLAG;
+            long |= is_flag;
+            !is_flag
+        })
+        .collect();
+    (rest.join(" "), long)
+}
+
+/// The stable identity of a quip: its category (directory name) and file name.
+/// Unlike the quip's number, this doesn't change when other quips are added.
+pub fn quip_id(dir_entry: &DirEntry) -> QuipId {
+    let path = dir_entry.path();
+    let category = path
+        .parent()
+        .and_then(|p| p.file_name())
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    (
+        category,
+        dir_entry.file_name().to_string_lossy().into_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::SqliteStore;
+
+    /// A throwaway audio directory with the given (category, file name)s.
+    fn audio_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let top_dir = std::env::temp_dir().join(format!("disquip-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&top_dir);
+        for (category, file_name) in files {
+            std::fs::create_dir_all(top_dir.join(category)).unwrap();
+            std::fs::write(top_dir.join(category).join(file_name), b"audio").unwrap();
+        }
+        top_dir
+    }
+
+    fn id(category: &str, file_name: &str) -> QuipId {
+        (category.to_string(), file_name.to_string())
+    }
+
+    #[test]
+    fn test_scan() {
+        let top_dir = audio_dir(
+            "scan",
+            &[
+                ("a1", "B.mp3"),
+                ("a1", "A.mp3"),
+                ("sw", "Hmm.mp3"),
+                ("sw", "No.OGG"),
+                ("sw", "notes.txt"),
+                (TRASH_DIR, "Gone.mp3"),
+            ],
+        );
+        let library = Library::scan(&top_dir, &Config::default(), None).unwrap();
+        assert_eq!(library.skipped, vec![top_dir.join("sw/notes.txt")]);
+        assert_eq!(library.describe_formats(), "3 mp3, 1 ogg");
+        let mut categories: Vec<&String> = library.file_map.keys().collect();
+        categories.sort();
+        assert_eq!(categories, vec!["a1", "sw"]);
+        assert_eq!(library.find_by_id(&id("a1", "B.mp3")).unwrap().0, 1);
+        assert_eq!(library.resolve_category("SW").unwrap(), "sw");
+        assert!(library.has_stem("a1", "a"));
+        assert!(!library.has_stem("sw", "a"));
+        // The test files aren't audio, but are still listed with a reason.
+        let info = &library.info[&id("sw", "Hmm.mp3")];
+        assert!(info.analysis.is_err());
+        assert_eq!(library.reused, 0);
+
+        // Unchanged files keep their previous details.
+        let mut previous = library;
+        previous
+            .info
+            .get_mut(&id("sw", "Hmm.mp3"))
+            .unwrap()
+            .analysis = Err("cached".to_string());
+        let library = Library::scan(&top_dir, &Config::default(), Some(&previous)).unwrap();
+        assert_eq!(
+            library.info[&id("sw", "Hmm.mp3")].analysis,
+            Err("cached".to_string())
+        );
+        assert_eq!(library.reused, 4);
+        std::fs::remove_dir_all(&top_dir).unwrap();
+    }
+
+    #[test]
+    fn test_long_quips() {
+        let top_dir = audio_dir("long", &[("a1", "Broken.mp3")]);
+        // About 2.6 and 0.26 seconds.
+        std::fs::write(top_dir.join("a1/Long.mp3"), audio::test_mp3(100)).unwrap();
+        std::fs::write(top_dir.join("a1/Short.mp3"), audio::test_mp3(10)).unwrap();
+        let library = Library::scan(&top_dir, &Config::default(), None).unwrap();
+
+        assert!(library.is_long(&id("a1", "Long.mp3"), Some(2.0)));
+        assert!(!library.is_long(&id("a1", "Long.mp3"), None));
+        assert!(!library.is_long(&id("a1", "Short.mp3"), Some(2.0)));
+        assert!(!library.is_long(&id("a1", "Broken.mp3"), Some(2.0)));
+        assert_eq!(library.long_quips(2.0), vec![&id("a1", "Long.mp3")]);
+        assert_eq!(library.long_quips(0.1).len(), 2);
+        std::fs::remove_dir_all(&top_dir).unwrap();
+    }
+
+    #[test]
+    fn test_random_pool_broken() {
+        let top_dir = audio_dir("broken", &[("a1", "A.mp3"), ("a1", "B.mp3")]);
+        let library = Library::scan(&top_dir, &Config::default(), None).unwrap();
+        let store = SqliteStore::in_memory().unwrap();
+        let pool_size = |broken_after| {
+            let filter = Filter {
+                broken_after,
+                ..Default::default()
+            };
+            library.random_pool(filter, &store).unwrap().len()
+        };
+        store
+            .record_failure(&id("a1", "A.mp3"), "damaged", 1)
+            .unwrap();
+        store
+            .record_failure(&id("a1", "A.mp3"), "damaged", 2)
+            .unwrap();
+        assert_eq!(pool_size(Some(3)), 2);
+        assert_eq!(pool_size(Some(2)), 1);
+        assert_eq!(pool_size(Some(0)), 2);
+        assert_eq!(pool_size(None), 2);
+        std::fs::remove_dir_all(&top_dir).unwrap();
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec![
+            "10 Foo.mp3",
+            "2 Bar.mp3",
+            "b.mp3",
+            "A.mp3",
+            "02 Baz.mp3",
+            "1.mp3",
+            "a10.mp3",
+            "a9.mp3",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            vec![
+                "1.mp3",
+                "2 Bar.mp3",
+                "02 Baz.mp3",
+                "10 Foo.mp3",
+                "A.mp3",
+                "a9.mp3",
+                "a10.mp3",
+                "b.mp3"
+            ]
+        );
+        assert_eq!(natural_cmp("a", "a"), Ordering::Equal);
+        // Equal but for leading zeros or case still have a fixed order.
+        assert_ne!(natural_cmp("02", "2"), Ordering::Equal);
+        assert_ne!(natural_cmp("A", "a"), Ordering::Equal);
+        assert_eq!(natural_cmp("a", "ab"), Ordering::Less);
+        assert_eq!(
+            natural_cmp("99999999999999999999999", "100000000000000000000000"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn test_order() {
+        let top_dir = audio_dir(
+            "order",
+            &[("a1", "10 Foo.mp3"), ("a1", "2 Bar.mp3"), ("a1", "1.mp3")],
+        );
+        std::fs::create_dir_all(top_dir.join("a2")).unwrap();
+        for (file_name, track) in [("A.mp3", "2"), ("B.mp3", "1/2")] {
+            std::fs::write(
+                top_dir.join("a2").join(file_name),
+                audio::test_mp3_track(5, track),
+            )
+            .unwrap();
+        }
+        std::fs::write(top_dir.join("a2/C.mp3"), audio::test_mp3(5)).unwrap();
+        let names = |library: &Library, category: &str| -> Vec<String> {
+            library.file_map[category]
+                .iter()
+                .map(|f| f.file_name().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        let library = Library::scan(&top_dir, &Config::default(), None).unwrap();
+        assert_eq!(names(&library, "a1"), ["1.mp3", "2 Bar.mp3", "10 Foo.mp3"]);
+        assert_eq!(names(&library, "a2"), ["A.mp3", "B.mp3", "C.mp3"]);
+
+        // Make "10 Foo" the oldest and "1" the newest.
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        let now = std::time::SystemTime::now();
+        for (file_name, days) in [("10 Foo.mp3", 3), ("2 Bar.mp3", 2), ("1.mp3", 1)] {
+            std::fs::File::options()
+                .write(true)
+                .open(top_dir.join("a1").join(file_name))
+                .unwrap()
+                .set_modified(now - day * days)
+                .unwrap();
+        }
+        let config: Config = toml::from_str(
+            r#"
+[categories.a1]
+order = "modified"
+
+[categories.a2]
+order = "metadata"
+"#,
+        )
+        .unwrap();
+        let library = Library::scan(&top_dir, &config, None).unwrap();
+        assert_eq!(names(&library, "a1"), ["10 Foo.mp3", "2 Bar.mp3", "1.mp3"]);
+        // C has no track number, so it comes last.
+        assert_eq!(names(&library, "a2"), ["B.mp3", "A.mp3", "C.mp3"]);
+        std::fs::remove_dir_all(&top_dir).unwrap();
+    }
+
+    #[test]
+    fn test_take_long_flag() {
+        assert_eq!(
+            take_long_flag("need  food --long"),
+            ("need food".to_string(), true)
+        );
+        assert_eq!(take_long_flag("--long 3"), ("3".to_string(), true));
+        assert_eq!(take_long_flag("3"), ("3".to_string(), false));
+        assert_eq!(take_long_flag(""), (String::new(), false));
+    }
+
+    #[test]
+    fn test_move_quip() {
+        let top_dir = audio_dir(
+            "move",
+            &[("a1", "A.mp3"), ("a1", "B.mp3"), ("sw", "Hmm.mp3")],
+        );
+        let library = Library::scan(&top_dir, &Config::default(), None).unwrap();
+        // Name taken, in the same category or another.
+        assert!(
+            library
+                .move_quip(&id("a1", "A.mp3"), &id("a1", "b.mp3"))
+                .is_err()
+        );
+        assert!(
+            library
+                .move_quip(&id("sw", "Hmm.mp3"), &id("a1", "A.mp3"))
+                .is_err()
+        );
+        assert!(
+            library
+                .move_quip(&id("a1", "A.mp3"), &id("nope", "A.mp3"))
+                .is_err()
+        );
+
+        library
+            .move_quip(&id("a1", "A.mp3"), &id("sw", "A.mp3"))
+            .unwrap();
+        library
+            .move_quip(&id("a1", "B.mp3"), &id("a1", "b.mp3"))
+            .unwrap();
+        let library = Library::scan(&top_dir, &Config::default(), None).unwrap();
+        assert!(library.find_by_id(&id("sw", "A.mp3")).is_some());
+        assert!(library.find_by_id(&id("a1", "b.mp3")).is_some());
+        assert!(library.find_by_id(&id("a1", "A.mp3")).is_none());
+        std::fs::remove_dir_all(&top_dir).unwrap();
+    }
+
+    #[test]
+    fn test_trash_quip() {
+        let top_dir = audio_dir("trash", &[("a1", "A.mp3"), ("a1", "B.mp3")]);
+        let library = Library::scan(&top_dir, &Config::default(), None).unwrap();
+        let path = library.trash_quip(&id("a1", "A.mp3"), 1000).unwrap();
+        assert_eq!(path, top_dir.join(TRASH_DIR).join("a1").join("A.mp3"));
+
+        // Trashing the same name again keeps both.
+        std::fs::write(top_dir.join("a1").join("A.mp3"), b"again").unwrap();
+        let path = library.trash_quip(&id("a1", "A.mp3"), 1000).unwrap();
+        assert_eq!(path, top_dir.join(TRASH_DIR).join("a1").join("1000-A.mp3"));
+        // Even within the same second.
+        std::fs::write(top_dir.join("a1").join("A.mp3"), b"and again").unwrap();
+        let path = library.trash_quip(&id("a1", "A.mp3"), 1000).unwrap();
+        assert_eq!(
+            path,
+            top_dir.join(TRASH_DIR).join("a1").join("1000-2-A.mp3")
+        );
+        assert_eq!(
+            std::fs::read(top_dir.join(TRASH_DIR).join("a1").join("1000-A.mp3")).unwrap(),
+            b"again"
+        );
+
+        let library = Library::scan(&top_dir, &Config::default(), None).unwrap();
+        assert_eq!(library.file_map["a1"].len(), 1);
+        std::fs::remove_dir_all(&top_dir).unwrap();
+    }
+}
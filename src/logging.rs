@@ -0,0 +1,78 @@
+use crate::Error;
+use crate::config::{LogFormat, LoggingConfig, Rotation};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+
+/// Start logging as configured. Keep the returned guard until the bot exits:
+/// log files are written in the background, and dropping it flushes them.
+pub fn init(config: &LoggingConfig) -> Result<Option<WorkerGuard>, Error> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(directives(&config.level)).map_err(|e| {
+            Error::Internal(format!("Invalid log level \"{}\": {}", config.level, e).into())
+        })?,
+    };
+
+    let (file, guard) = match &config.directory {
+        Some(directory) => {
+            let rotation = match config.rotation {
+                Rotation::Hourly => rolling::Rotation::HOURLY,
+                Rotation::Daily => rolling::Rotation::DAILY,
+                Rotation::Never => rolling::Rotation::NEVER,
+            };
+            let mut builder = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix("disquip")
+                .filename_suffix("log");
+            if config.max_files > 0 {
+                builder = builder.max_log_files(config.max_files);
+            }
+            let appender = builder.build(directory).map_err(|e| {
+                Error::Internal(format!("Can't log to {}: {}", directory.display(), e).into())
+            })?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (Some(writer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let registry = tracing_subscriber::registry().with(filter);
+    let initialized = match config.format {
+        LogFormat::Plain => registry
+            .with(fmt::layer())
+            .with(file.map(|w| fmt::layer().with_ansi(false).with_writer(w)))
+            .try_init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json())
+            .with(file.map(|w| fmt::layer().json().with_writer(w)))
+            .try_init(),
+    };
+    initialized.map_err(|e| Error::Internal(e.into()))?;
+    Ok(guard)
+}
+
+/// Filter directives for the configured level. A bare level applies to the
+/// bot only, so serenity and songbird don't drown it out; anything else is
+/// taken as a full filter.
+fn directives(level: &str) -> String {
+    if level.contains(['=', ',']) {
+        level.to_string()
+    } else {
+        format!("warn,{}={}", env!("CARGO_CRATE_NAME"), level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directives() {
+        assert_eq!(directives("debug"), "warn,disquip_bot_rs=debug");
+        assert_eq!(directives("info,songbird=debug"), "info,songbird=debug");
+        assert!(EnvFilter::try_new(directives("debug")).is_ok());
+    }
+}
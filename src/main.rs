@@ -1,6 +1,37 @@
+mod audio;
 mod civ;
+mod config;
+mod cooldown;
+mod error;
+mod health;
+mod history;
+mod http;
+mod library;
+mod logging;
+mod metrics;
+mod nowplaying;
+mod perms;
+mod prefixes;
+mod review;
+mod shuffle;
+mod stats;
+mod store;
+mod suggest;
+mod tags;
+mod upload;
 use crate::civ::{GAME_MODES, draw_leaders, draw_map, draw_modes, draw_settings};
-use poise::serenity_prelude as serenity;
+use crate::config::Config;
+use crate::cooldown::RateLimiter;
+use crate::error::Error;
+use crate::history::History;
+use crate::library::{Library, quip_id, take_long_flag};
+use crate::perms::Access;
+use crate::review::{Status, Submission};
+use crate::shuffle::{Filter, ShuffleBags};
+use crate::stats::{Play, Window};
+use crate::store::{SqliteStore, Store};
+use crate::suggest::{closest, did_you_mean};
+use poise::serenity_prelude::{self as serenity, Mentionable};
 use rand::{
     Rng,
     distr::{Distribution, Uniform},
@@ -9,135 +40,307 @@ use songbird::SerenityInit;
 use std::{
     collections::HashMap,
     env,
-    fs::{DirEntry, read_dir},
+    fs::DirEntry,
+    path::Path,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, Instant},
 };
+use tracing::{debug, error, info, trace, warn};
 // Event related imports to detect track creation failures.
 use songbird::events::{Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent};
-type FileMap = HashMap<String, Vec<DirEntry>>;
+// Stable identity of a quip: (category, file name). See quip_id.
+type QuipId = (String, String);
 
-type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::PrefixContext<'a, Data, Error>;
 type GenericContext<'a> = poise::Context<'a, Data, Error>;
 
 struct Data {
-    // Map of quip categories to directory entries.
-    pub file_map: FileMap,
-    pub map_len: usize,
+    // The quips themselves. See Data::library and Data::reload.
+    library: RwLock<Arc<Library>>,
+    pub config: Arc<Config>,
+    // Cooldown tracking for quip playback.
+    pub limiter: RateLimiter,
+    // Persistent storage for guild settings and user data.
+    pub store: Arc<dyn Store>,
+    // Prefixes set with "!prefix", kept in memory as every message needs
+    // one. Changes are written through to the store.
+    prefixes: RwLock<HashMap<serenity::GuildId, String>>,
+    // Recent plays per guild, for "!again" and "!history".
+    pub history: History,
+    // Shuffle bags for "!r", so quips don't repeat too soon.
+    pub bags: ShuffleBags,
+    // For downloading uploaded quips.
+    pub http: reqwest::Client,
+    // Counters for the metrics endpoint, if enabled.
+    pub metrics: Arc<metrics::Metrics>,
+    // The "now playing" message of each guild, if enabled.
+    pub now_playing: Arc<nowplaying::Board>,
+    // Held while changing the audio directory, see Data::edit_library.
+    edits: tokio::sync::Mutex<()>,
 }
 
 impl Data {
-    fn new(top_dir: String) -> Data {
-        // Initialize the file map and a counter for the total number of DirEntries.
-        let mut file_map: HashMap<String, Vec<DirEntry>> = HashMap::new();
-        let mut map_len: usize = 0;
-
-        // Loop over directories within the top_dir and fill out the HashMap.
-        let result = read_dir(top_dir).unwrap();
-        for r in result {
-            let u = r.unwrap();
-            // Only work with directories.
-            if u.file_type().unwrap().is_dir() {
-                // Iterate over the files and place in the HashMap using the
-                // directory's name as a key.
-                let key = u.file_name().into_string().unwrap();
-                let files = read_dir(u.path()).unwrap();
-                for f in files {
-                    let _f = f.unwrap();
-                    match file_map.entry(key.to_owned()) {
-                        std::collections::hash_map::Entry::Occupied(mut oe) => {
-                            oe.get_mut().push(_f);
-                        }
-                        std::collections::hash_map::Entry::Vacant(ve) => {
-                            ve.insert(vec![_f]);
-                        }
-                    }
-                    map_len += 1;
-                }
-            }
-        }
+    fn new(top_dir: String, config: Config, store: Arc<dyn Store>) -> Result<Data, Error> {
+        let library = Library::scan(Path::new(&top_dir), &config, None)?;
+        info!(
+            quips = library.file_map.values().map(|v| v.len()).sum::<usize>(),
+            categories = library.file_map.len(),
+            formats = %library.describe_formats(),
+            "Loaded library"
+        );
+        warn_long_quips(&library, &config);
+        let metrics = Arc::new(metrics::Metrics::default());
+        metrics.library_scanned(&library);
+        let prefixes = RwLock::new(store.prefixes()?);
+        Ok(Data {
+            library: RwLock::new(Arc::new(library)),
+            config: Arc::new(config),
+            limiter: RateLimiter::default(),
+            store,
+            prefixes,
+            history: History::default(),
+            bags: ShuffleBags::default(),
+            http: reqwest::Client::new(),
+            metrics,
+            now_playing: Arc::default(),
+            edits: tokio::sync::Mutex::new(()),
+        })
+    }
 
-        // Sort.
-        for val in file_map.values_mut() {
-            val.sort_by_key(|a| a.path());
+    /// The command prefix in effect for the given guild: one set with
+    /// "!prefix" wins over the configuration file. DMs use the default.
+    fn prefix(&self, guild_id: Option<serenity::GuildId>) -> String {
+        let Some(guild_id) = guild_id else {
+            return self.config.prefix.clone();
+        };
+        let prefixes = self.prefixes.read().unwrap_or_else(PoisonError::into_inner);
+        match prefixes.get(&guild_id) {
+            Some(prefix) => prefix.clone(),
+            None => self.config.prefix(guild_id).to_string(),
         }
-        Data { file_map, map_len }
     }
 
-    /// Get a DirEntry from the given index. The index is effectively an
-    /// index into the imaginary vector of all DirEntries in the FileMap
-    /// concatenated together. Also returns the chosen category and index within.
-    fn get_from_global_index(&self, idx: usize) -> Result<(&DirEntry, String, usize), Error> {
-        let mut visited: usize = 0;
+    /// Set, or with None clear, the prefix for the given guild.
+    fn set_prefix(&self, guild_id: serenity::GuildId, prefix: Option<&str>) -> Result<(), Error> {
+        self.store.set_prefix(guild_id, prefix)?;
+        let mut prefixes = self
+            .prefixes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        match prefix {
+            Some(prefix) => prefixes.insert(guild_id, prefix.to_string()),
+            None => prefixes.remove(&guild_id),
+        };
+        Ok(())
+    }
 
-        for (cat, vec) in self.file_map.iter() {
-            let _idx = idx - visited;
-            let _len = vec.len();
-            if (idx - visited) < _len {
-                return Ok((&vec[_idx], cat.to_owned(), _idx));
-            };
+    /// The current library snapshot. Hold on to it for the duration of a
+    /// command so that everything the command sees is consistent.
+    fn library(&self) -> Arc<Library> {
+        // A command that panicked can't have left a half-swapped Arc behind,
+        // so a poisoned lock is still good to use. Likewise below.
+        self.library
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
 
-            visited += _len;
-        }
-        Err(format!("The provided idx ({}) to get_from_global_index is too large. Index must be between 0 and {}.", idx, self.map_len).into())
+    /// Re-read the audio directory and swap in the new library, e.g. after a
+    /// quip has been added. Commands already running keep their old snapshot.
+    /// New files are decoded in full, so the scan runs off the async workers.
+    async fn reload(&self) -> Result<Arc<Library>, Error> {
+        let previous = self.library();
+        let config = self.config.clone();
+        let library = tokio::task::spawn_blocking(move || {
+            Library::scan(&previous.top_dir, &config, Some(&previous))
+        })
+        .await??;
+        let library = Arc::new(library);
+        warn_long_quips(&library, &self.config);
+        self.metrics.library_scanned(&library);
+        *self.library.write().unwrap_or_else(PoisonError::into_inner) = library.clone();
+        Ok(library)
     }
 
-    /// Get a vector from the file_map from the given key ("cat" for "category").
-    /// If the key is not present, return an error which eventually gets floated up
-    /// to the user.
-    fn get_vec(&self, cat: &String) -> Result<&Vec<DirEntry>, Error> {
-        if let Some(cat_vec) = self.file_map.get(cat) {
-            return Ok(cat_vec);
-        };
+    /// Change the audio directory and reload the library. Edits happen one at
+    /// a time, so checks like "is this name free?" made by the edit against
+    /// the library it's given still hold when it acts on them.
+    async fn edit_library<T>(
+        &self,
+        edit: impl FnOnce(&Library) -> Result<T, Error>,
+    ) -> Result<(T, Arc<Library>), Error> {
+        let _guard = self.edits.lock().await;
+        let result = edit(&self.library())?;
+        Ok((result, self.reload().await?))
+    }
+}
+
+/// Log the quips over the top-level playback limit, which only play with
+/// "--long" or for members with a long role.
+fn warn_long_quips(library: &Library, config: &Config) {
+    let Some(limit) = config.playback.limit() else {
+        return;
+    };
+    let long = library.long_quips(limit);
+    if !long.is_empty() {
+        let names: Vec<String> = long.iter().map(|(c, f)| format!("{}: {}", c, f)).collect();
+        warn!(
+            count = long.len(),
+            limit,
+            quips = %names.join(", "),
+            "Quips longer than the playback limit need \"--long\""
+        );
+    }
+}
+
+/// Whether the author is a bot admin: either they have one of the guild's
+/// configured admin roles, or they are a Discord administrator of the guild.
+async fn is_admin(ctx: GenericContext<'_>) -> bool {
+    let Some(member) = ctx.author_member().await else {
+        return false;
+    };
+    let guild = ctx.guild();
+    member_is_admin(&ctx.data().config, guild.as_deref(), &member)
+}
+
+/// Whether a member is a bot admin, see is_admin. The guild comes from the
+/// cache and is only needed for the owner and Discord permissions.
+fn member_is_admin(
+    config: &Config,
+    guild: Option<&serenity::Guild>,
+    member: &serenity::Member,
+) -> bool {
+    if config.is_admin(member.guild_id, &member.roles) {
+        return true;
+    }
+    guild.is_some_and(|guild| {
+        guild.owner_id == member.user.id || guild.member_permissions(member).administrator()
+    })
+}
+
+/// Command check run before every command: enforces the per-guild role and
+/// channel restrictions from the configuration.
+async fn command_check(ctx: GenericContext<'_>) -> Result<bool, Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(true);
+    };
+    let roles = match ctx.author_member().await {
+        Some(member) => member.roles.clone(),
+        None => Vec::new(),
+    };
+    let access = perms::check(
+        ctx.data().config.guild(guild_id),
+        ctx.command().category.as_deref(),
+        ctx.channel_id(),
+        &roles,
+        is_admin(ctx).await,
+    );
+    match access {
+        Access::Allowed => Ok(true),
+        Access::Denied(reason) => Err(reason.into()),
+        Access::Ignored => Ok(false),
+    }
+}
 
-        // TODO: How does "into" work?
-        Err(format!("The provided category {:?} is invalid. Use \"!list\" with no arguments to get valid categories.", cat).into())
+/// For anything that plays a quip: enforce the configured cooldowns, which
+/// admins are exempt from. Called once the quip has been found and may be
+/// played, so that a typo doesn't cost a play.
+async fn take_cooldown(ctx: &Context<'_>) -> Result<(), Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(());
+    };
+    if is_admin(poise::Context::Prefix(*ctx)).await {
+        return Ok(());
     }
+    check_cooldown(ctx.data(), guild_id, ctx.channel_id(), ctx.author().id)
+}
+
+/// Count a play against the configured cooldowns, or explain how long to
+/// wait if one of them is still running.
+fn check_cooldown(
+    data: &Data,
+    guild_id: serenity::GuildId,
+    channel_id: serenity::ChannelId,
+    user_id: serenity::UserId,
+) -> Result<(), Error> {
+    let checked = data.limiter.check(
+        data.config.cooldowns(guild_id),
+        guild_id.get(),
+        channel_id.get(),
+        user_id.get(),
+        Instant::now(),
+    );
+    checked.map_err(|wait| {
+        format!(
+            "Easy there! You can play another quip in {:.1} seconds.",
+            wait.as_secs_f64()
+        )
+        .into()
+    })
 }
 
 /// Play a quip!
-#[poise::command(prefix_command, guild_only = true, hide_in_help = true)]
-async fn join_and_play(ctx: Context<'_>, num: usize) -> Result<(), Error> {
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    hide_in_help = true,
+    category = "Quips"
+)]
+async fn join_and_play(ctx: Context<'_>, #[rest] quip: String) -> Result<(), Error> {
+    // Get the chosen_file, either by number or by name.
+    let (quip, long) = take_long_flag(&quip);
+    let command = ctx.invoked_command_name().to_string();
+    let library = ctx.data().library();
+    let (_, chosen_file) = library.find_quip(&command, &quip)?;
+    let cutoff = playback_limit(&ctx, &library, &quip_id(chosen_file), long).await?;
+    take_cooldown(&ctx).await?;
+
     // Join the voice channel.
     join(&ctx).await?;
-
-    // Get the chosen_file.
-    let command = ctx.invoked_command_name().to_string();
-    let file_vec = ctx.data().get_vec(&command)?;
-    let attempt_chosen_file = file_vec.get(num - 1);
-    let chosen_file = match attempt_chosen_file {
-        Some(chosen_file) => chosen_file,
-        None => {
-            ctx.say(format!("The given integer \"{:?}\" is invalid. Valid integers for the {:?} command range from 1 to {:?}", num, command, file_vec.len()))
-            .await?;
-            return Ok(());
-        }
-    };
-    play(&ctx, chosen_file).await?;
+    play(&ctx, &library, chosen_file, cutoff, false).await?;
     Ok(())
 }
 
+/// The songbird voice manager, registered when the client is built.
+async fn voice_manager(ctx: &serenity::Context) -> Result<Arc<songbird::Songbird>, Error> {
+    songbird::get(ctx)
+        .await
+        .ok_or_else(|| Error::Voice("Voice support isn't set up, so I can't play quips.".into()))
+}
+
 async fn join(ctx: &Context<'_>) -> Result<(), Error> {
-    // Get user's voice channel.
-    let guild = ctx.guild().unwrap().to_owned();
-    let user_id = ctx.author().id;
-    let voice_states = guild.voice_states.get(&user_id);
+    // Get user's voice channel. The guild can be missing from the cache
+    // briefly, e.g. right after the bot (re)connects.
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
+    let voice_channel = {
+        let guild = ctx.guild().ok_or_else(|| {
+            Error::Voice(
+                "I can't see this server's voice channels yet, try again in a moment.".into(),
+            )
+        })?;
+        guild
+            .voice_states
+            .get(&ctx.author().id)
+            .map(|state| state.channel_id)
+    };
 
-    let Some(voice_states) = voice_states else {
+    let Some(voice_channel) = voice_channel else {
         return Err("You must be in a voice channel to play quips!".into());
     };
 
-    let Some(channel_id) = voice_states.channel_id else {
-        return Err("Failed to get voice channel ID (which is very, very odd...)".into());
+    let Some(channel_id) = voice_channel else {
+        return Err(Error::Voice(
+            "Failed to get voice channel ID (which is very, very odd...)".into(),
+        ));
     };
 
     let songbird_id = songbird::id::ChannelId::from(channel_id);
-    let manager = songbird::get(ctx.serenity_context())
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let manager = voice_manager(ctx.serenity_context()).await?;
 
     // Exit early if we're already in the channel.
-    if let Some(handler_lock) = manager.get(ctx.guild_id().unwrap()) {
+    if let Some(handler_lock) = manager.get(guild_id) {
         let handler = handler_lock.lock().await;
 
         if let Some(current_id) = handler.current_channel()
@@ -149,26 +352,95 @@ async fn join(ctx: &Context<'_>) -> Result<(), Error> {
 
     // It seems to be fine if there are multiple join calls, probably no need
     // to add our own conditional here.
-    let handler_lock = manager.join(guild.id, songbird_id).await?;
-    let mut handler = handler_lock.lock().await;
-    // Ensure there's only ever a single event/error handler:
-    handler.remove_all_global_events();
-    handler.add_global_event(TrackEvent::Error.into(), TrackErrorNotifier);
+    manager.join(guild_id, songbird_id).await?;
+    info!(%guild_id, %channel_id, user_id = %ctx.author().id, "Joined voice channel");
     Ok(())
 }
 
-struct TrackErrorNotifier;
+/// Reports how a track went. Failures are told to the channel the quip was
+/// asked for in and recorded, so quips that keep failing can be left out of
+/// "!r"; playing to the end, or until stopped, clears the record.
+#[derive(Clone)]
+struct TrackReporter {
+    http: Arc<serenity::Http>,
+    store: Arc<dyn Store>,
+    metrics: Arc<metrics::Metrics>,
+    guild_id: serenity::GuildId,
+    channel_id: serenity::ChannelId,
+    quip: QuipId,
+    number: usize,
+    // The guild's random.broken_after, to say when "!r" starts skipping it.
+    broken_after: u32,
+    // The guild's command prefix, for the same.
+    prefix: String,
+}
+
+#[serenity::async_trait]
+impl VoiceEventHandler for TrackReporter {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let EventContext::Track(track_list) = ctx else {
+            return None;
+        };
+        for (state, handle) in *track_list {
+            let songbird::tracks::PlayMode::Errored(error) = &state.playing else {
+                if let Err(e) = self.store.clear_failures(&self.quip) {
+                    error!(error = %e, "Failed to clear failures");
+                }
+                continue;
+            };
+            self.metrics.playback_error();
+            let (category, file_name) = &self.quip;
+            warn!(
+                guild_id = %self.guild_id,
+                channel_id = %self.channel_id,
+                track = %handle.uuid(),
+                category,
+                file_name,
+                %error,
+                "Track failed"
+            );
+            let mut message = format!(
+                "Couldn't play quip \"{} {}\" ({}): {}.",
+                category,
+                self.number,
+                file_name,
+                audio::describe_play_error(error)
+            );
+            match self
+                .store
+                .record_failure(&self.quip, &error.to_string(), stats::now())
+            {
+                Ok(failures) if self.broken_after > 0 && failures >= self.broken_after.into() => {
+                    message.push_str(&format!(
+                        " It has failed {} times in a row, so \"{}r\" skips it until it plays again.",
+                        failures, self.prefix
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => error!(error = %e, "Failed to record failure"),
+            }
+            if let Err(e) = self.channel_id.say(&self.http, message).await {
+                error!(error = %e, "Failed to report track error");
+            }
+        }
+
+        None
+    }
+}
+
+/// Longest value Discord accepts in an embed field.
+const EMBED_FIELD_LIMIT: usize = 1024;
+
+/// Stops a track when it reaches the playback limit, see playback_limit.
+struct Cutoff;
 
 #[serenity::async_trait]
-impl VoiceEventHandler for TrackErrorNotifier {
+impl VoiceEventHandler for Cutoff {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
         if let EventContext::Track(track_list) = ctx {
-            for (state, handle) in *track_list {
-                println!(
-                    "Track {:?} encountered an error: {:?}",
-                    handle.uuid(),
-                    state.playing
-                );
+            for (_, handle) in *track_list {
+                // The track may well have ended by itself already.
+                let _ = handle.stop();
             }
         }
 
@@ -176,52 +448,353 @@ impl VoiceEventHandler for TrackErrorNotifier {
     }
 }
 
-async fn play(ctx: &Context<'_>, dir_entry: &DirEntry) -> Result<(), Error> {
-    let manager = songbird::get(ctx.serenity_context())
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+/// Whether the author may play quips over the playback limit without asking
+/// for them with "--long".
+async fn may_play_long(ctx: &Context<'_>) -> bool {
+    let Some(guild_id) = ctx.guild_id() else {
+        return false;
+    };
+    let roles = match ctx.author_member().await {
+        Some(member) => member.roles.clone(),
+        None => Vec::new(),
+    };
+    let admin = is_admin(poise::Context::Prefix(*ctx)).await;
+    perms::may_play_long(ctx.data().config.guild(guild_id), &roles, admin)
+}
+
+/// Work out how long a quip may play: Some(limit) to cut it off there, or None
+/// to play it in full. Quips known to be over the limit play in full, but only
+/// when asked for with "--long" or for members who may play long quips.
+async fn playback_limit(
+    ctx: &Context<'_>,
+    library: &Library,
+    quip: &QuipId,
+    long: bool,
+) -> Result<Option<Duration>, Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
+    let Some(limit) = ctx.data().config.playback(guild_id).limit() else {
+        return Ok(None);
+    };
+    if !library.is_long(quip, Some(limit)) {
+        return Ok(Some(Duration::from_secs_f64(limit)));
+    }
+    if long || may_play_long(ctx).await {
+        return Ok(None);
+    }
+    Err(format!(
+        "{} Add \"--long\" to play it anyway.",
+        over_limit(library, quip, limit)
+    )
+    .into())
+}
+
+/// Explain that a quip is over the server's playback limit.
+fn over_limit(library: &Library, quip: &QuipId, limit: f64) -> String {
+    format!(
+        "{}: {} is {:.1} seconds long, over this server's limit of {} seconds.",
+        quip.0,
+        quip.1,
+        library.seconds(quip).unwrap_or_default(),
+        limit
+    )
+}
+
+/// Play a quip, stopping it after `cutoff` if given (see playback_limit).
+/// With `announce`, say which quip it is; quips picked by the bot, like with
+/// "!r", should be. The transcript is shown too, if the guild wants it. If the
+/// guild has a now-playing message, that says it all instead.
+async fn play(
+    ctx: &Context<'_>,
+    library: &Library,
+    dir_entry: &DirEntry,
+    cutoff: Option<Duration>,
+    announce: bool,
+) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
+    let data = ctx.data();
+    let quip = quip_id(dir_entry);
+    let idx = library.find_by_id(&quip).map_or(0, |(idx, _)| idx);
+    let track = start_track(
+        ctx.serenity_context(),
+        data,
+        guild_id,
+        ctx.channel_id(),
+        dir_entry,
+        idx + 1,
+        cutoff,
+    )
+    .await?;
+
+    let transcript = library
+        .info
+        .get(&quip)
+        .and_then(|i| i.analysis.as_ref().ok())
+        .and_then(|a| a.transcript.as_deref())
+        .filter(|_| data.config.transcripts(guild_id));
+    if data.config.now_playing(guild_id) {
+        let playing = nowplaying::Playing {
+            quip: quip.clone(),
+            number: idx + 1,
+            requester: ctx.author().id,
+            length: play_length(library, &quip, cutoff),
+            cutoff,
+            transcript: transcript.map(str::to_string),
+            position: Duration::ZERO,
+            finished: false,
+        };
+        show_now_playing(
+            ctx.serenity_context(),
+            data,
+            guild_id,
+            ctx.channel_id(),
+            &track,
+            playing,
+        )
+        .await?;
+    } else if announce || transcript.is_some() {
+        let mut message = format!("Playing quip \"{} {}\" ({})", quip.0, idx + 1, quip.1);
+        if let Some(transcript) = transcript {
+            message.push('\n');
+            // Long quip names leave less room in the message.
+            let room = serenity::constants::MESSAGE_CODE_LIMIT - message.chars().count();
+            message.push_str(&audio::quote_transcript(
+                transcript,
+                audio::MAX_TRANSCRIPT_CHARS.min(room),
+            ));
+        }
+        ctx.say(message).await?;
+    }
+    record_play(data, guild_id, ctx.author().id, quip);
+    Ok(())
+}
 
-    // TODO: Double unwrapping here... Better error handling is more better.
-    let handler_lock = manager.get(ctx.guild_id().unwrap()).unwrap();
+/// Start playing quip number `number` in the guild's voice call, in place of
+/// whatever was playing, and stop it after `cutoff` if given. Errors playing
+/// it are reported in the given channel.
+async fn start_track(
+    ctx: &serenity::Context,
+    data: &Data,
+    guild_id: serenity::GuildId,
+    channel_id: serenity::ChannelId,
+    dir_entry: &DirEntry,
+    number: usize,
+    cutoff: Option<Duration>,
+) -> Result<songbird::tracks::TrackHandle, Error> {
+    let manager = voice_manager(ctx).await?;
+    let handler_lock = manager.get(guild_id).ok_or("I'm not in a voice channel.")?;
     let mut handler = handler_lock.lock().await;
 
     let file = songbird::input::File::new(dir_entry.path());
-    handler.play_only_input(file.into());
+    let track = handler.play_only_input(file.into());
+    drop(handler);
+    if let Some(cutoff) = cutoff {
+        track.add_event(Event::Delayed(cutoff), Cutoff)?;
+    }
+    let reporter = TrackReporter {
+        http: ctx.http.clone(),
+        store: data.store.clone(),
+        metrics: data.metrics.clone(),
+        guild_id,
+        channel_id,
+        quip: quip_id(dir_entry),
+        number,
+        broken_after: data.config.random(guild_id).broken_after,
+        prefix: data.prefix(Some(guild_id)),
+    };
+    track.add_event(Event::Track(TrackEvent::Error), reporter.clone())?;
+    track.add_event(Event::Track(TrackEvent::End), reporter)?;
+    Ok(track)
+}
+
+/// How long a quip will play: its duration, or the cutoff if that's sooner.
+fn play_length(library: &Library, quip: &QuipId, cutoff: Option<Duration>) -> Option<Duration> {
+    let seconds = library.seconds(quip).map(Duration::from_secs_f64);
+    match (seconds, cutoff) {
+        (Some(seconds), Some(cutoff)) => Some(seconds.min(cutoff)),
+        (seconds, cutoff) => seconds.or(cutoff),
+    }
+}
+
+/// Remember a play for "!again" and "!history", and in the statistics.
+fn record_play(data: &Data, guild_id: serenity::GuildId, user_id: serenity::UserId, quip: QuipId) {
+    let played_at = stats::now();
+    data.history.push(
+        guild_id,
+        history::Entry {
+            quip: quip.clone(),
+            user_id,
+            played_at,
+        },
+    );
 
+    info!(
+        %guild_id,
+        %user_id,
+        category = %quip.0,
+        file_name = %quip.1,
+        "Played quip"
+    );
+    data.metrics.played(&quip.0);
+    // Statistics are nice to have, so don't fail the command over them.
+    let (category, file_name) = quip;
+    let recorded = data.store.record_play(&Play {
+        guild_id,
+        user_id,
+        category,
+        file_name,
+        played_at,
+    });
+    if let Err(e) = recorded {
+        error!(error = %e, "Failed to record play");
+    }
+}
+
+/// Show a quip on the guild's now-playing message and keep it up to date while
+/// the track plays. The message is edited in place when it's in the same
+/// channel, and otherwise moves to this one.
+async fn show_now_playing(
+    ctx: &serenity::Context,
+    data: &Data,
+    guild_id: serenity::GuildId,
+    channel_id: serenity::ChannelId,
+    track: &songbird::tracks::TrackHandle,
+    playing: nowplaying::Playing,
+) -> Result<(), Error> {
+    let (play_id, stale) = data.now_playing.start(guild_id, channel_id, playing);
+    // Listen before posting, in case a short quip ends meanwhile.
+    let updater = nowplaying::Updater {
+        board: data.now_playing.clone(),
+        http: ctx.http.clone(),
+        guild_id,
+        play_id,
+    };
+    track.add_event(
+        Event::Periodic(nowplaying::UPDATE_PERIOD, None),
+        updater.clone(),
+    )?;
+    track.add_event(Event::Track(TrackEvent::End), updater.clone())?;
+    track.add_event(Event::Track(TrackEvent::Error), updater)?;
+
+    if let Some((stale_channel, stale_message)) = stale {
+        // It may have been deleted already, which is fine.
+        let _ = stale_channel.delete_message(ctx, stale_message).await;
+    }
+    let Some((message, playing)) = data.now_playing.update(guild_id, play_id, |_| ()) else {
+        return Ok(());
+    };
+    if let Some(message) = message
+        && nowplaying::edit(&ctx.http, message, &playing).await.is_ok()
+    {
+        return Ok(());
+    }
+    // Not posted yet, or the old message is gone.
+    let (embed, buttons) = nowplaying::message(&playing);
+    let posted = channel_id
+        .send_message(
+            ctx,
+            serenity::CreateMessage::new()
+                .embed(embed)
+                .components(vec![buttons]),
+        )
+        .await?;
+    data.now_playing
+        .set_message(guild_id, (channel_id, posted.id));
     Ok(())
 }
 
 /// Show help menu.
 #[poise::command(prefix_command)]
 pub async fn help(ctx: GenericContext<'_>, command: Option<String>) -> Result<(), Error> {
+    let prefix = ctx.data().prefix(ctx.guild_id());
+    let extra_text_at_bottom = format!(
+        "\
+Type \"{p}<category> <number>\" (e.g., \"{p}a1 1\") to play a quip!
+Type \"{p}<category> <name>\" (e.g., \"{p}a3 need food\") to play a quip by name.
+Type \"{p}list\" to discover available quip categories.
+Type \"{p}list <category>\" to get available quip numbers for the given category.
+Type \"{p}help <command>\" for more info on a command.",
+        p = prefix
+    );
     let config = poise::builtins::HelpConfiguration {
-        extra_text_at_bottom: "\
-Type \"!<category> <number>\" (e.g., \"a1 1\") to play a quip!
-Type \"!list\" to discover available quip categories.
-Type \"!list <category>\" to get available quip numbers for the given category.
-Type \"!help <command>\" for more info on a command.",
+        extra_text_at_bottom: &extra_text_at_bottom,
         ..Default::default()
     };
     poise::builtins::help(ctx, command.as_deref(), config).await?;
     Ok(())
 }
 
+/// Show or change the command prefix for this server.
+///
+/// Examples:
+///   - `!prefix` to show the current prefix.
+///   - `!prefix ?` to use "?" instead, after which this command becomes "?prefix".
+///   - `!prefix reset` to go back to the default prefix.
+///
+/// Mentioning the bot always works as a prefix too, e.g. "@DisQuip prefix reset".
+#[poise::command(prefix_command, guild_only = true, category = "Admin")]
+async fn prefix(ctx: Context<'_>, new_prefix: Option<String>) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
+    let data = ctx.data();
+
+    let Some(new_prefix) = new_prefix else {
+        ctx.say(format!(
+            "The command prefix for this server is `{}`",
+            data.prefix(Some(guild_id))
+        ))
+        .await?;
+        return Ok(());
+    };
+
+    if new_prefix == "reset" {
+        data.set_prefix(guild_id, None)?;
+    } else {
+        prefixes::validate(&new_prefix)?;
+        data.set_prefix(guild_id, Some(&new_prefix))?;
+    }
+    ctx.say(format!(
+        "The command prefix for this server is now `{}`",
+        data.prefix(Some(guild_id))
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Look up the prefix for the guild a message was sent in.
+async fn dynamic_prefix(
+    ctx: poise::PartialContext<'_, Data, Error>,
+) -> Result<Option<String>, Error> {
+    Ok(Some(ctx.data.prefix(ctx.guild_id)))
+}
+
 /// List quip categories or list quips for a given command.
 /// E.g., "!list" or "!list a1"
-#[poise::command(prefix_command, guild_only = true)]
+#[poise::command(prefix_command, guild_only = true, category = "Quips")]
 async fn list(ctx: Context<'_>, cat: Option<String>) -> Result<(), Error> {
     let data = ctx.data();
     match cat {
         Some(_cat) => {
-            let cat_vec = data.get_vec(&_cat)?;
+            let library = data.library();
+            let cat_vec = library.get_vec(&_cat)?;
+            let limit = ctx.guild_id().and_then(|g| data.config.playback(g).limit());
             let mut help_str = format!("Available quips for category \"{}\":\n```\n", _cat);
             for (idx, item) in cat_vec.iter().enumerate() {
+                let long = if library.is_long(&quip_id(item), limit) {
+                    " (long)"
+                } else {
+                    ""
+                };
                 help_str.push_str(
                     format!(
-                        "{}: {:?}\n",
+                        "{}: {:?}{}\n",
                         idx as u32 + 1,
-                        item.file_name().into_string().unwrap()
+                        item.file_name().to_string_lossy(),
+                        long
                     )
                     .as_str(),
                 );
@@ -237,7 +810,7 @@ async fn list(ctx: Context<'_>, cat: Option<String>) -> Result<(), Error> {
             }
         }
         None => {
-            let mut key_vec: Vec<String> = data.file_map.keys().cloned().collect();
+            let mut key_vec: Vec<String> = data.library().file_map.keys().cloned().collect();
             key_vec.sort();
             let mut help_str = String::from("Quip categories:\n");
             for key in key_vec {
@@ -279,66 +852,1377 @@ fn split_str(to_split: &str) -> Vec<String> {
 }
 
 /// Disconnect the bot from its current voice channel.
-#[poise::command(prefix_command, guild_only = true)]
+#[poise::command(prefix_command, guild_only = true, category = "Admin")]
 async fn disconnect(ctx: Context<'_>) -> Result<(), Error> {
-    let guild = ctx.guild().unwrap().to_owned();
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
 
-    let manager = songbird::get(ctx.serenity_context())
-        .await
-        .expect("Songbird Voice client placed in at initialisation.")
-        .clone();
+    let manager = voice_manager(ctx.serenity_context()).await?;
 
-    manager.remove(guild.id).await?;
+    manager.remove(guild_id).await?;
+    info!(%guild_id, user_id = %ctx.author().id, "Left voice channel");
     Ok(())
 }
 
-/// Aka "!r" or "!rand." Play a random quip.
+/// Rename, move or delete quips: "!quip <rename|move|delete> ..."
 ///
-/// E.g., `!r` to play a globally random quip or `!r a1` to play a random
-/// quip from the "a1" category.
-#[poise::command(prefix_command, guild_only = true, aliases("r", "rand"))]
-async fn random(ctx: Context<'_>, cat: Option<String>) -> Result<(), Error> {
-    // Join the voice channel.
-    join(&ctx).await?;
+/// Examples:
+///   - `!quip rename a3 2 "I Need Food"` to rename the second "a3" quip.
+///   - `!quip move a3 2 misc` to move it to the "misc" category.
+///   - `!quip delete a3 2` to move it to the trash.
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    category = "Admin",
+    subcommands("quip_rename", "quip_move", "quip_delete"),
+    subcommand_required
+)]
+async fn quip(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Rename a quip: "!quip rename <category> <quip> <new name>."
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    category = "Admin",
+    rename = "rename"
+)]
+async fn quip_rename(
+    ctx: Context<'_>,
+    cat: String,
+    quip: String,
+    #[rest] new_name: String,
+) -> Result<(), Error> {
+    let new_name = new_name.trim();
+    // Quotes are optional for the last argument.
+    let new_name = new_name
+        .strip_prefix('"')
+        .and_then(|n| n.strip_suffix('"'))
+        .unwrap_or(new_name)
+        .trim();
+    upload::check_name(new_name)?;
+    let library = ctx.data().library();
+    let (_, dir_entry) = library.find_quip(&cat, &quip)?;
+    let from = quip_id(dir_entry);
+    let file_name = match dir_entry.path().extension() {
+        Some(extension) => format!("{}.{}", new_name, extension.to_string_lossy()),
+        None => new_name.to_string(),
+    };
+    let to = (from.0.clone(), file_name);
+    move_quip(ctx, &from, &to).await
+}
 
+/// Move a quip to another category: "!quip move <category> <quip> <new category>."
+#[poise::command(prefix_command, guild_only = true, category = "Admin", rename = "move")]
+async fn quip_move(
+    ctx: Context<'_>,
+    cat: String,
+    quip: String,
+    new_cat: String,
+) -> Result<(), Error> {
+    let library = ctx.data().library();
+    let (_, dir_entry) = library.find_quip(&cat, &quip)?;
+    let from = quip_id(dir_entry);
+    let to = (library.resolve_category(&new_cat)?.clone(), from.1.clone());
+    move_quip(ctx, &from, &to).await
+}
+
+/// Rename and/or move a quip on disk, and keep the plays, favourites and tags
+/// recorded for it.
+async fn move_quip(ctx: Context<'_>, from: &QuipId, to: &QuipId) -> Result<(), Error> {
+    let data = ctx.data();
+    let (_, library) = data
+        .edit_library(|library| {
+            library.move_quip(from, to)?;
+            // The file has moved either way, so don't fail the whole command
+            // over the bookkeeping.
+            if let Err(e) = data.store.rename_quip(from, to) {
+                error!(?from, ?to, error = %e, "Failed to rename quip in the store");
+            }
+            data.history.rename(from, to);
+            data.bags.rename(from, to);
+            Ok(())
+        })
+        .await?;
+    ctx.say(format!(
+        "Moved {}: {} to \"{} {}\" ({}).",
+        from.0,
+        from.1,
+        to.0,
+        quip_number(&library, to),
+        to.1
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Move a quip to the trash: "!quip delete <category> <quip>."
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    category = "Admin",
+    rename = "delete",
+    aliases("rm", "remove")
+)]
+async fn quip_delete(ctx: Context<'_>, cat: String, #[rest] quip: String) -> Result<(), Error> {
+    let library = ctx.data().library();
+    let (_, dir_entry) = library.find_quip(&cat, &quip)?;
+    let id = quip_id(dir_entry);
+    ctx.data()
+        .edit_library(|library| library.trash_quip(&id, stats::now()))
+        .await?;
+    ctx.say(format!(
+        "Deleted {}: {}. It can be recovered from the `{}` directory on the host.",
+        id.0,
+        id.1,
+        library::TRASH_DIR
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Re-read the audio directory, e.g. after adding quips on the host.
+#[poise::command(prefix_command, guild_only = true, category = "Admin")]
+async fn reload(ctx: Context<'_>) -> Result<(), Error> {
+    let library = ctx.data().reload().await?;
+    let quips: usize = library.file_map.values().map(|v| v.len()).sum();
+    let mut reply = format!(
+        "Reloaded {} quips in {} categories ({}).",
+        quips,
+        library.file_map.len(),
+        library.describe_formats()
+    );
+    if !library.skipped.is_empty() {
+        reply.push_str(&format!(
+            " Skipped {} files in unsupported formats; supported formats are {}.",
+            library.skipped.len(),
+            audio::EXTENSIONS.join(", ")
+        ));
+    }
+
+    // Categories double as command names, which are fixed at startup.
+    let commands = &ctx.framework().options().commands;
+    let known: Vec<&String> = commands
+        .iter()
+        .filter(|c| c.name == "join_and_play")
+        .flat_map(|c| c.aliases.iter())
+        .collect();
+    let mut new: Vec<&String> = library
+        .file_map
+        .keys()
+        .filter(|k| !known.contains(k))
+        .collect();
+    if !new.is_empty() {
+        new.sort();
+        let new: Vec<&str> = new.iter().map(|k| k.as_str()).collect();
+        reply.push_str(&format!(
+            " New categories ({}) can be used with \"{}r\" right away, but need a restart to become commands.",
+            new.join(", "),
+            ctx.prefix()
+        ));
+    }
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+/// Aka "!r" or "!rand." Play a random quip.
+///
+/// E.g., `!r` to play a globally random quip, `!r a1` to play a random
+/// quip from the "a1" category, `!r a1 a2` to pick from both "a1" and "a2",
+/// or `!r -sw` to pick from anything but "sw". Tags work the same way:
+/// `!r #victory` picks from quips tagged "victory" in any category, and
+/// `!r #funny -#loud` from those tagged "funny" but not "loud". Quips over
+/// the playback limit are left out unless you add `--long`.
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    aliases("r", "rand"),
+    category = "Quips"
+)]
+async fn random(ctx: Context<'_>, categories: Vec<String>) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
     let data = ctx.data();
+    let library = data.library();
+    let mut filter = Filter::parse(&categories);
+    if !filter.long && !may_play_long(&ctx).await {
+        filter.max_seconds = data.config.playback(guild_id).limit();
+    }
+    filter.broken_after = Some(data.config.random(guild_id).broken_after);
+    let pool = library.random_pool(filter, data.store.as_ref())?;
+    let pool_ids: Vec<QuipId> = pool.iter().map(|(_, d)| quip_id(d)).collect();
+
+    let settings = data.config.random(guild_id);
+    let counts: Option<HashMap<QuipId, u64>> = if settings.weighted {
+        Some(data.store.play_counts(guild_id)?)
+    } else {
+        None
+    };
     // Use a block here because the rng needs dropped before the await later.
-    let chosen_file: &DirEntry;
-    let chosen_category: String;
-    let idx: usize;
-    {
+    let chosen = {
         // Docs say this is a fast, pre-initialized generator. So it should
         // be cheap to get it, and it's probably not worth fighting through
         // the thread safety stuff to put the rng on the Data struct as a field.
         let mut rng = rand::rng();
-        if let Some(_cat) = cat {
-            let file_vec = data.get_vec(&_cat)?;
-            idx = rng.random_range(0..file_vec.len());
-            chosen_file = &file_vec[idx];
-            chosen_category = _cat;
+        data.bags.choose(
+            &mut rng,
+            guild_id,
+            &pool_ids,
+            settings.shuffle,
+            counts.as_ref(),
+        )
+    };
+    let Some(chosen) = chosen else {
+        return Err(format!(
+            "There are no quips to choose from. Use \"{0}list\" and \"{0}tags\" to see what's available.",
+            ctx.prefix()
+        )
+        .into());
+    };
+    let (_, chosen_file) = pool[chosen];
+    // Long quips are only in the pool if they're allowed.
+    let cutoff = playback_limit(&ctx, &library, &pool_ids[chosen], true).await?;
+    take_cooldown(&ctx).await?;
+
+    // Join the voice channel.
+    join(&ctx).await?;
+    play(&ctx, &library, chosen_file, cutoff, true).await?;
+    Ok(())
+}
+
+/// Aka "!download." Send a quip as an attachment: "!get <category> <quip>."
+///
+/// E.g., `!get a3 2` or `!get a3 need food`. Handy to preview a quip without
+/// joining voice.
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    aliases("download"),
+    category = "Quips"
+)]
+async fn get(ctx: Context<'_>, cat: String, #[rest] quip: String) -> Result<(), Error> {
+    let library = ctx.data().library();
+    let (idx, dir_entry) = library.find_quip(&cat, &quip)?;
+    let (category, file_name) = quip_id(dir_entry);
+
+    // Discord doesn't accept large files, and there's no transcoding to make
+    // them smaller, so refuse up front rather than failing half way.
+    let size = dir_entry.metadata()?.len();
+    let limit = upload::discord_limit(ctx.guild().map(|g| g.premium_tier).unwrap_or_default());
+    if size > limit {
+        return Err(format!(
+            "\"{}\" is too large to send here ({:.1} MB, the limit is {} MB).",
+            file_name,
+            size as f64 / (1024.0 * 1024.0),
+            limit / (1024 * 1024)
+        )
+        .into());
+    }
+    take_cooldown(&ctx).await?;
+
+    let attachment = serenity::CreateAttachment::path(dir_entry.path()).await?;
+    ctx.send(
+        poise::CreateReply::default()
+            .content(format!("Quip \"{} {}\" ({})", category, idx + 1, file_name))
+            .attachment(attachment),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Show details about a quip's file: "!info <category> <quip>."
+///
+/// E.g., `!info a3 2` shows its format, duration, peak level and so on.
+#[poise::command(prefix_command, guild_only = true, category = "Quips")]
+async fn info(ctx: Context<'_>, cat: String, #[rest] quip: String) -> Result<(), Error> {
+    let library = ctx.data().library();
+    let (idx, dir_entry) = library.find_quip(&cat, &quip)?;
+    let id = quip_id(dir_entry);
+    let info = library.info.get(&id).ok_or_else(|| {
+        format!(
+            "This quip hasn't been scanned yet. Try again after \"{}reload\".",
+            ctx.prefix()
+        )
+    })?;
+    let limit = ctx
+        .guild_id()
+        .and_then(|g| ctx.data().config.playback(g).limit());
+    let long = if library.is_long(&id, limit) {
+        " (long, needs \"--long\")"
+    } else {
+        ""
+    };
+
+    let mut embed = serenity::CreateEmbed::new()
+        .title(format!("Quip \"{} {}\"", id.0, idx + 1))
+        .field("File", &id.1, false);
+    match &info.analysis {
+        Ok(analysis) => {
+            embed = embed
+                .field("Format", &analysis.codec, true)
+                .field(
+                    "Duration",
+                    format!("{:.2} s{}", analysis.seconds, long),
+                    true,
+                )
+                .field(
+                    "Sample rate",
+                    analysis
+                        .sample_rate
+                        .map_or("unknown".to_string(), |r| format!("{} Hz", r)),
+                    true,
+                )
+                .field(
+                    "Channels",
+                    analysis
+                        .channels
+                        .map_or("unknown".to_string(), |c| c.to_string()),
+                    true,
+                )
+                .field("Peak", audio::describe_peak(analysis.peak), true)
+                .field(
+                    "Opus passthrough",
+                    if analysis.passthrough { "yes" } else { "no" },
+                    true,
+                );
+            if let Some(transcript) = &analysis.transcript {
+                embed = embed.field(
+                    "Transcript",
+                    audio::quote_transcript(transcript, audio::MAX_TRANSCRIPT_CHARS),
+                    false,
+                );
+            }
+        }
+        Err(reason) => {
+            embed = embed.field("Problem", reason, false);
+        }
+    }
+    embed = embed.field("Size", format!("{:.1} KB", info.size as f64 / 1000.0), true);
+    if let Some(modified) = info.modified {
+        embed = embed.field("Modified", format!("<t:{}:f>", modified), true);
+    }
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Aka "!replay." Play a recent quip again: "!again [n]."
+///
+/// Examples:
+///   - `!again` to replay the last quip, e.g. a gem found with "!r".
+///   - `!again 3` to replay the third most recent quip. See "!history".
+///   - `!again 3 --long` if it's over the playback limit.
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    aliases("replay"),
+    category = "Quips"
+)]
+async fn again(ctx: Context<'_>, #[rest] args: Option<String>) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
+    let (n, long) = take_long_flag(args.as_deref().unwrap_or_default());
+    let n: usize = if n.is_empty() {
+        1
+    } else {
+        n.parse().map_err(|_| {
+            format!(
+                "\"{}\" is not a play number. See \"{}history\".",
+                n,
+                ctx.prefix()
+            )
+        })?
+    };
+    let Some(entry) = ctx.data().history.get(guild_id, n) else {
+        return Err(if n == 1 {
+            String::from("Nothing has been played yet.")
         } else {
-            (chosen_file, chosen_category, idx) =
-                data.get_from_global_index(rng.random_range(0..data.map_len))?;
+            format!(
+                "There is no play number {} in the history. See \"{}history\".",
+                n,
+                ctx.prefix()
+            )
+        }
+        .into());
+    };
+
+    let library = ctx.data().library();
+    let Some((_, dir_entry)) = library.find_by_id(&entry.quip) else {
+        return Err(format!("{}: {} no longer exists.", entry.quip.0, entry.quip.1).into());
+    };
+    let cutoff = playback_limit(&ctx, &library, &entry.quip, long).await?;
+    take_cooldown(&ctx).await?;
+    join(&ctx).await?;
+    play(&ctx, &library, dir_entry, cutoff, true).await?;
+    Ok(())
+}
+
+/// List recently played quips and who played them: "!history [n]."
+#[poise::command(prefix_command, guild_only = true, category = "Quips")]
+async fn history(ctx: Context<'_>, n: Option<usize>) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
+    let recent = ctx.data().history.recent(guild_id, n.unwrap_or(10));
+    let description = if recent.is_empty() {
+        String::from("Nothing has been played yet.")
+    } else {
+        // Mentions in embeds show up as names without pinging anyone, and
+        // Discord renders the timestamps in each reader's time zone.
+        recent
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| {
+                format!(
+                    "{}. {}: {} by {} <t:{}:R>",
+                    idx + 1,
+                    entry.quip.0,
+                    entry.quip.1,
+                    entry.user_id.mention(),
+                    entry.played_at
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+    let embed = serenity::CreateEmbed::new()
+        .title("Recently played quips")
+        .description(description)
+        .footer(serenity::CreateEmbedFooter::new(format!(
+            "Use \"{}again <number>\" to play one again.",
+            ctx.prefix()
+        )));
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Show quip statistics for this server: "!stats [window] [@user]."
+///
+/// Examples:
+///   - `!stats` for all-time statistics.
+///   - `!stats week` for the past week. Also "today" (the past 24 hours),
+///     "month" (the past 30 days) and "all".
+///   - `!stats @someone` for someone's favourite quips.
+#[poise::command(prefix_command, guild_only = true, category = "Quips")]
+async fn stats(ctx: Context<'_>, args: Vec<String>) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
+    // Parsed by hand: left to poise, a mistyped window would be taken for a
+    // user, and the error wouldn't say which windows there are.
+    let mut window = Window::All;
+    let mut user = None;
+    for arg in &args {
+        match arg.parse::<Window>() {
+            Ok(parsed) => window = parsed,
+            Err(unknown) => {
+                let found = <serenity::User as serenity::ArgumentConvert>::convert(
+                    ctx.serenity_context(),
+                    Some(guild_id),
+                    Some(ctx.channel_id()),
+                    arg,
+                )
+                .await;
+                user = Some(found.map_err(|_| unknown.to_string())?);
+            }
+        }
+    }
+    let since = window.since(stats::now());
+    let store = &ctx.data().store;
+
+    let quip_name =
+        |((category, file_name), n): (QuipId, u64)| (format!("{}: {}", category, file_name), n);
+
+    let embed = if let Some(user) = user {
+        let favourites: Vec<_> = store
+            .top_quips(guild_id, Some(user.id), since, stats::TOP_N)?
+            .into_iter()
+            .map(quip_name)
+            .collect();
+        serenity::CreateEmbed::new()
+            .title(format!(
+                "Quip stats for {} over {}",
+                user.name,
+                window.describe()
+            ))
+            .field(
+                "Favourite quips",
+                stats::ranked(&favourites, EMBED_FIELD_LIMIT),
+                false,
+            )
+    } else {
+        let top_quips: Vec<_> = store
+            .top_quips(guild_id, None, since, stats::TOP_N)?
+            .into_iter()
+            .map(quip_name)
+            .collect();
+        // Mentions in embeds show up as names without pinging anyone.
+        let top_users: Vec<_> = store
+            .top_users(guild_id, since, stats::TOP_N)?
+            .into_iter()
+            .map(|(user_id, n)| (user_id.mention().to_string(), n))
+            .collect();
+        let categories = store.category_totals(guild_id, since, stats::TOP_N)?;
+        let favourites: Vec<_> = store
+            .top_quips(guild_id, Some(ctx.author().id), since, stats::TOP_N)?
+            .into_iter()
+            .map(quip_name)
+            .collect();
+        serenity::CreateEmbed::new()
+            .title(format!("Quip stats for {}", window.describe()))
+            .field(
+                "Most played quips",
+                stats::ranked(&top_quips, EMBED_FIELD_LIMIT),
+                false,
+            )
+            .field(
+                "Most active users",
+                stats::ranked(&top_users, EMBED_FIELD_LIMIT),
+                false,
+            )
+            .field(
+                "Most played categories",
+                stats::ranked(&categories, EMBED_FIELD_LIMIT),
+                false,
+            )
+            .field(
+                "Your favourite quips",
+                stats::ranked(&favourites, EMBED_FIELD_LIMIT),
+                false,
+            )
+    };
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Play one of your favourite quips: "!fav <number>" or "!fav r" for a random one.
+///
+/// Examples:
+///   - `!fav add a3 2` (or `!fav add a3 need food`) to add a favourite.
+///   - `!fav list` (or just `!fav`) to list your favourites and their numbers.
+///   - `!fav 1` to play your first favourite.
+///   - `!fav r` to play a random favourite.
+///   - `!fav 1 --long` if it's over the playback limit.
+///   - `!fav remove 1` to remove your first favourite.
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    category = "Quips",
+    subcommands("fav_add", "fav_remove", "fav_list")
+)]
+async fn fav(ctx: Context<'_>, #[rest] which: Option<String>) -> Result<(), Error> {
+    let (which, long) = take_long_flag(which.as_deref().unwrap_or_default());
+    if which.is_empty() {
+        return send_favourites(ctx).await;
+    }
+    let favourites = ctx.data().store.favourites(ctx.author().id)?;
+    if favourites.is_empty() {
+        return Err(format!(
+            "You don't have any favourites yet. Add one with \"{}fav add <category> <number>\".",
+            ctx.prefix()
+        )
+        .into());
+    }
+
+    let idx = if ["r", "rand", "random"].contains(&which.as_str()) {
+        rand::rng().random_range(0..favourites.len())
+    } else {
+        favourite_index(&which, favourites.len())?
+    };
+
+    let library = ctx.data().library();
+    let Some((_, dir_entry)) = library.find_by_id(&favourites[idx]) else {
+        return Err(format!(
+            "Favourite {} ({}: {}) no longer exists. Remove it with \"{}fav remove {}\".",
+            idx + 1,
+            favourites[idx].0,
+            favourites[idx].1,
+            ctx.prefix(),
+            idx + 1
+        )
+        .into());
+    };
+    let cutoff = playback_limit(&ctx, &library, &favourites[idx], long).await?;
+    take_cooldown(&ctx).await?;
+    join(&ctx).await?;
+    play(&ctx, &library, dir_entry, cutoff, false).await?;
+    Ok(())
+}
+
+/// Parse a 1-based favourite number into a 0-based index.
+fn favourite_index(which: &str, len: usize) -> Result<usize, Error> {
+    match which.parse::<usize>() {
+        Ok(num) if (1..=len).contains(&num) => Ok(num - 1),
+        _ => Err(format!(
+            "\"{}\" is not a valid favourite. Use a number from 1 to {}, or \"r\" for a random favourite.",
+            which, len
+        )
+        .into()),
+    }
+}
+
+/// Add a quip to your favourites: "!fav add <category> <number or name>."
+#[poise::command(prefix_command, guild_only = true, category = "Quips", rename = "add")]
+async fn fav_add(ctx: Context<'_>, cat: String, #[rest] quip: String) -> Result<(), Error> {
+    let library = ctx.data().library();
+    let (_, dir_entry) = library.find_quip(&cat, &quip)?;
+    let id = quip_id(dir_entry);
+    if ctx.data().store.add_favourite(ctx.author().id, &id)? {
+        let count = ctx.data().store.favourites(ctx.author().id)?.len();
+        ctx.say(format!(
+            "Added {}: {} as favourite {}. Play it with \"{}fav {}\".",
+            id.0,
+            id.1,
+            count,
+            ctx.prefix(),
+            count
+        ))
+        .await?;
+    } else {
+        ctx.say(format!("{}: {} is already a favourite.", id.0, id.1))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Remove a favourite by its number: "!fav remove <number>."
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    category = "Quips",
+    rename = "remove",
+    aliases("rm", "delete")
+)]
+async fn fav_remove(ctx: Context<'_>, which: String) -> Result<(), Error> {
+    let favourites = ctx.data().store.favourites(ctx.author().id)?;
+    let idx = favourite_index(&which, favourites.len())?;
+    ctx.data()
+        .store
+        .remove_favourite(ctx.author().id, &favourites[idx])?;
+    ctx.say(format!(
+        "Removed {}: {} from your favourites.",
+        favourites[idx].0, favourites[idx].1
+    ))
+    .await?;
+    Ok(())
+}
+
+/// List your favourite quips.
+#[poise::command(prefix_command, guild_only = true, category = "Quips", rename = "list")]
+async fn fav_list(ctx: Context<'_>) -> Result<(), Error> {
+    send_favourites(ctx).await
+}
+
+async fn send_favourites(ctx: Context<'_>) -> Result<(), Error> {
+    let favourites = ctx.data().store.favourites(ctx.author().id)?;
+    let description = if favourites.is_empty() {
+        format!(
+            "No favourites yet. Add one with \"{}fav add <category> <number>\".",
+            ctx.prefix()
+        )
+    } else {
+        let library = ctx.data().library();
+        favourites
+            .iter()
+            .enumerate()
+            .map(|(idx, id)| {
+                // Flag favourites whose file has been removed or renamed.
+                let missing = if library.find_by_id(id).is_some() {
+                    ""
+                } else {
+                    " (missing)"
+                };
+                format!("{}. {}: {}{}", idx + 1, id.0, id.1, missing)
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+    let embed = serenity::CreateEmbed::new()
+        .title(format!("Favourite quips of {}", ctx.author().name))
+        .description(description);
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// List quip tags and how many quips carry each.
+///
+/// Use "!r #<tag>" to play a random quip with that tag.
+#[poise::command(prefix_command, guild_only = true, category = "Quips")]
+async fn tags(ctx: Context<'_>) -> Result<(), Error> {
+    let data = ctx.data();
+    let library = data.library();
+    let mut tags = library.tags(data.store.as_ref())?;
+    // Don't count tags of quips that have since been removed.
+    tags.retain(|id, _| library.find_by_id(id).is_some());
+    let counts = tags::counts(&tags);
+    let description = if counts.is_empty() {
+        format!(
+            "No quips are tagged yet. Tag one with \"{}tag add <category> <quip> <tags>\".",
+            ctx.prefix()
+        )
+    } else {
+        counts
+            .iter()
+            .map(|(tag, count)| format!("#{} ({})", tag, count))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    let embed = serenity::CreateEmbed::new()
+        .title("Quip tags")
+        .description(description)
+        .footer(serenity::CreateEmbedFooter::new(format!(
+            "Use \"{}r #<tag>\" to play a random quip with a tag.",
+            ctx.prefix()
+        )));
+    ctx.send(poise::CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// Show or change a quip's tags: "!tag <category> <quip>."
+///
+/// Examples:
+///   - `!tag a3 2` (or `!tag a3 "need food"`) to show a quip's tags.
+///   - `!tag add a3 2 funny hungry` to tag a quip.
+///   - `!tag remove a3 2 hungry` to untag it again.
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    category = "Quips",
+    subcommands("tag_add", "tag_remove")
+)]
+async fn tag(ctx: Context<'_>, cat: String, #[rest] quip: String) -> Result<(), Error> {
+    let library = ctx.data().library();
+    let (_, dir_entry) = library.find_quip(&cat, &quip)?;
+    let id = quip_id(dir_entry);
+    let tags = library.tags(ctx.data().store.as_ref())?;
+    match tags.get(&id) {
+        Some(quip_tags) if !quip_tags.is_empty() => {
+            let quip_tags: Vec<String> = quip_tags.iter().map(|t| format!("#{}", t)).collect();
+            ctx.say(format!(
+                "{}: {} is tagged {}.",
+                id.0,
+                id.1,
+                quip_tags.join(" ")
+            ))
+            .await?;
+        }
+        _ => {
+            ctx.say(format!("{}: {} has no tags.", id.0, id.1)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Tag a quip: "!tag add <category> <quip> <tags...>."
+#[poise::command(prefix_command, guild_only = true, category = "Quips", rename = "add")]
+async fn tag_add(
+    ctx: Context<'_>,
+    cat: String,
+    quip: String,
+    tags: Vec<String>,
+) -> Result<(), Error> {
+    let library = ctx.data().library();
+    let (_, dir_entry) = library.find_quip(&cat, &quip)?;
+    let id = quip_id(dir_entry);
+    let tags = parse_tags(&tags, ctx.prefix())?;
+    let mut added = Vec::new();
+    for tag in &tags {
+        if ctx.data().store.add_tag(&id, tag)? {
+            added.push(format!("#{}", tag));
+        }
+    }
+    if added.is_empty() {
+        ctx.say(format!("{}: {} already has those tags.", id.0, id.1))
+            .await?;
+    } else {
+        ctx.say(format!(
+            "Tagged {}: {} with {}.",
+            id.0,
+            id.1,
+            added.join(" ")
+        ))
+        .await?;
+    }
+    Ok(())
+}
+
+/// Untag a quip: "!tag remove <category> <quip> <tags...>."
+#[poise::command(
+    prefix_command,
+    guild_only = true,
+    category = "Quips",
+    rename = "remove",
+    aliases("rm", "delete")
+)]
+async fn tag_remove(
+    ctx: Context<'_>,
+    cat: String,
+    quip: String,
+    tags: Vec<String>,
+) -> Result<(), Error> {
+    let library = ctx.data().library();
+    let (_, dir_entry) = library.find_quip(&cat, &quip)?;
+    let id = quip_id(dir_entry);
+    let tags = parse_tags(&tags, ctx.prefix())?;
+    let mut removed = Vec::new();
+    let mut in_sidecar = Vec::new();
+    for tag in &tags {
+        if ctx.data().store.remove_tag(&id, tag)? {
+            removed.push(format!("#{}", tag));
+        } else if library
+            .sidecar_tags
+            .get(&id)
+            .is_some_and(|t| t.contains(tag))
+        {
+            in_sidecar.push(format!("#{}", tag));
         }
+    }
+    let mut reply = if removed.is_empty() {
+        format!("{}: {} didn't have those tags.", id.0, id.1)
+    } else {
+        format!("Removed {} from {}: {}.", removed.join(" "), id.0, id.1)
+    };
+    if !in_sidecar.is_empty() {
+        reply.push_str(&format!(
+            " {} can only be removed from {} by the bot's owner.",
+            in_sidecar.join(" "),
+            tags::SIDECAR
+        ));
+    }
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+/// Normalize the tags given to "!tag add" or "!tag remove", rejecting the
+/// whole command if any is invalid.
+fn parse_tags(tags: &[String], prefix: &str) -> Result<Vec<String>, Error> {
+    if tags.is_empty() {
+        return Err(format!(
+            "Give at least one tag, e.g. \"{}tag add a3 2 funny\".",
+            prefix
+        )
+        .into());
+    }
+    Ok(tags
+        .iter()
+        .map(|t| tags::normalize(t))
+        .collect::<Result<Vec<String>, String>>()?)
+}
+
+/// Add a quip by attaching an audio file: "!upload <category> [name]."
+///
+/// The quip is named after the file unless a name is given, e.g.
+/// `!upload a3 I Need Food` with "clip.mp3" attached adds "I Need Food.mp3" to
+/// the "a3" category.
+#[poise::command(prefix_command, guild_only = true, category = "Quips")]
+async fn upload(ctx: Context<'_>, cat: String, #[rest] name: Option<String>) -> Result<(), Error> {
+    let guild_id = ctx
+        .guild_id()
+        .ok_or("This command only works in a server.")?;
+    let data = ctx.data();
+    let roles = match ctx.author_member().await {
+        Some(member) => member.roles.clone(),
+        None => Vec::new(),
+    };
+    let admin = is_admin(poise::Context::Prefix(ctx)).await;
+    // Trusted members add quips directly, everyone else goes through review
+    // if the guild has a review channel.
+    let trusted = perms::may_upload(data.config.guild(guild_id), &roles, admin);
+    let review_channel = data.config.guild(guild_id).and_then(|g| g.review_channel);
+    if !trusted && review_channel.is_none() {
+        return Err("Sorry, you don't have a role that is allowed to upload quips.".into());
+    }
+
+    let Some(attachment) = ctx.msg.attachments.first() else {
+        return Err("Attach an audio file to the message to upload it.".into());
+    };
+    let library = data.library();
+    let category = library.resolve_category(&cat)?.clone();
+    let file_name = upload::file_name(name.as_deref(), &attachment.filename)?;
+    let path = Path::new(&file_name);
+    let stem = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    // Quips are played by name without their extension, so a name is taken
+    // even if only the extension differs.
+    if library.has_stem(&category, &stem) {
+        return Err(format!(
+            "Category `{}` already has a quip named \"{}\".",
+            category, stem
+        )
+        .into());
+    }
+
+    let limits = &data.config.upload;
+    if u64::from(attachment.size) > limits.max_bytes {
+        return Err(format!(
+            "The file is too large, the limit is {} KB.",
+            limits.max_bytes / 1000
+        )
+        .into());
+    }
+    let bytes = upload::download(&data.http, &attachment.url, limits.max_bytes).await?;
+    // Decoding the whole clip is slow, so keep it off the async workers.
+    let seconds = {
+        let (bytes, config) = (bytes.clone(), data.config.clone());
+        tokio::task::spawn_blocking(move || upload::validate(bytes, &extension, &config.upload))
+            .await??
     };
+
+    if let Some(review_channel) = review_channel.filter(|_| !trusted) {
+        let submitted_at = stats::now();
+        let submission = Submission {
+            id: 0,
+            guild_id,
+            user_id: ctx.author().id,
+            channel_id: ctx.channel_id(),
+            category,
+            pending_file: review::pending_name(ctx.author().id, submitted_at, &file_name),
+            file_name,
+            seconds,
+            status: Status::Pending,
+            submitted_at,
+        };
+        return submit_for_review(ctx, review_channel, submission, bytes).await;
+    }
+
+    let id = (category, file_name);
+    let (_, library) = data
+        .edit_library(|library| {
+            // Someone may have taken the name during the download.
+            if library.has_stem(&id.0, &stem) {
+                return Err(
+                    format!("Category `{}` already has a quip named \"{}\".", id.0, stem).into(),
+                );
+            }
+            upload::save(&library.top_dir, &id.0, &id.1, &bytes)
+        })
+        .await?;
     ctx.say(format!(
-        "Playing quip \"{} {}\" ({})",
-        chosen_category,
-        // Convert to 1-based indexing.
-        idx as u32 + 1,
-        chosen_file.file_name().into_string().unwrap()
+        "Added quip \"{} {}\" ({}, {:.1} seconds).",
+        id.0,
+        quip_number(&library, &id),
+        id.1,
+        seconds
     ))
     .await?;
-    play(&ctx, chosen_file).await?;
     Ok(())
 }
 
+/// The 1-based number of a quip as shown by "!list", or nothing if it's gone.
+fn quip_number(library: &Library, id: &QuipId) -> String {
+    library
+        .find_by_id(id)
+        .map(|(idx, _)| (idx + 1).to_string())
+        .unwrap_or_default()
+}
+
+/// Put an uploaded quip in the pending directory and ask the moderators in the
+/// review channel to approve or reject it.
+async fn submit_for_review(
+    ctx: Context<'_>,
+    review_channel: serenity::ChannelId,
+    mut submission: Submission,
+    bytes: Vec<u8>,
+) -> Result<(), Error> {
+    let data = ctx.data();
+    let top_dir = data.library().top_dir.clone();
+    std::fs::create_dir_all(top_dir.join(review::PENDING_DIR))?;
+    upload::save(
+        &top_dir,
+        review::PENDING_DIR,
+        &submission.pending_file,
+        &bytes,
+    )?;
+    submission.id = data.store.add_submission(&submission)?;
+
+    let embed = serenity::CreateEmbed::new()
+        .title("Quip submitted for review")
+        .field("Category", &submission.category, true)
+        .field("Name", &submission.file_name, true)
+        .field("Length", format!("{:.1} seconds", submission.seconds), true)
+        .field(
+            "Submitted by",
+            submission.user_id.mention().to_string(),
+            true,
+        );
+    let buttons = serenity::CreateActionRow::Buttons(vec![
+        serenity::CreateButton::new(review::button_id(Status::Approved, submission.id))
+            .label("Approve")
+            .style(serenity::ButtonStyle::Success),
+        serenity::CreateButton::new(review::button_id(Status::Rejected, submission.id))
+            .label("Reject")
+            .style(serenity::ButtonStyle::Danger),
+    ]);
+    let message = serenity::CreateMessage::new()
+        .embed(embed)
+        .add_file(serenity::CreateAttachment::bytes(
+            bytes,
+            submission.file_name.clone(),
+        ))
+        .components(vec![buttons]);
+    review_channel.send_message(ctx, message).await?;
+
+    ctx.say("Thanks! Your quip will be available once a moderator approves it.")
+        .await?;
+    Ok(())
+}
+
+/// Log and count a command about to run, and note when it started.
+async fn log_command(ctx: GenericContext<'_>) {
+    ctx.data().metrics.command(&ctx.command().qualified_name);
+    ctx.set_invocation_data(Instant::now()).await;
+    info!(
+        command = ctx.command().qualified_name,
+        invoked = ctx.invoked_command_name(),
+        guild_id = ?ctx.guild_id(),
+        channel_id = %ctx.channel_id(),
+        user_id = %ctx.author().id,
+        "Running command"
+    );
+    debug!(invocation = ctx.invocation_string(), "Command invocation");
+}
+
+/// Record how long a command took, see log_command.
+async fn time_command(ctx: GenericContext<'_>) {
+    let started = ctx.invocation_data::<Instant>().await.map(|s| *s);
+    if let Some(started) = started {
+        ctx.data()
+            .metrics
+            .command_finished(&ctx.command().qualified_name, started.elapsed());
+    }
+}
+
+/// Count the voice channels the bot is connected to.
+async fn voice_connections(manager: &songbird::Songbird) -> usize {
+    // Don't hold on to the manager's map while waiting for the calls.
+    let calls: Vec<_> = manager.iter().map(|(_, call)| call).collect();
+    let mut connected = 0;
+    for call in calls {
+        if call.lock().await.current_channel().is_some() {
+            connected += 1;
+        }
+    }
+    connected
+}
+
+/// Log gateway events, the interesting ones at info level and the rest at
+/// trace level.
+fn log_event(event: &serenity::FullEvent) {
+    match event {
+        serenity::FullEvent::Ready { data_about_bot } => info!(
+            user = %data_about_bot.user.name,
+            guilds = data_about_bot.guilds.len(),
+            "Connected to Discord"
+        ),
+        serenity::FullEvent::Resume { .. } => info!("Resumed gateway session"),
+        serenity::FullEvent::ShardStageUpdate { event } => info!(
+            shard = %event.shard_id,
+            old = ?event.old,
+            new = ?event.new,
+            "Shard stage changed"
+        ),
+        serenity::FullEvent::GuildCreate { guild, is_new } => info!(
+            guild_id = %guild.id,
+            name = %guild.name,
+            is_new = ?is_new,
+            "Guild available"
+        ),
+        serenity::FullEvent::GuildDelete { incomplete, .. } => info!(
+            guild_id = %incomplete.id,
+            unavailable = incomplete.unavailable,
+            "Guild gone"
+        ),
+        _ => trace!(event = event.snake_case_name(), "Gateway event"),
+    }
+}
+
+/// Handle gateway events that aren't commands: clicks on the review and
+/// now-playing buttons. All of them are logged.
+async fn event_handler(
+    ctx: &serenity::Context,
+    event: &serenity::FullEvent,
+    _framework: poise::FrameworkContext<'_, Data, Error>,
+    data: &Data,
+) -> Result<(), Error> {
+    log_event(event);
+    let serenity::FullEvent::InteractionCreate {
+        interaction: serenity::Interaction::Component(component),
+    } = event
+    else {
+        return Ok(());
+    };
+    let custom_id = &component.data.custom_id;
+    if let Some((decision, id)) = review::parse_button_id(custom_id) {
+        review_submission(ctx, data, component, decision, id).await?;
+    } else if let Some(control) = nowplaying::parse_button_id(custom_id) {
+        // Discord only waits three seconds for a response, and starting a
+        // replay can take longer. Problems are reported in a follow-up.
+        component
+            .create_response(ctx, serenity::CreateInteractionResponse::Acknowledge)
+            .await?;
+        if let Err(reason) = control_playback(ctx, data, component, control).await {
+            if reason.should_log() {
+                warn!(
+                    guild_id = ?component.guild_id,
+                    user_id = %component.user.id,
+                    error = %reason,
+                    "Now-playing control failed"
+                );
+            }
+            let followup = serenity::CreateInteractionResponseFollowup::new()
+                .content(reason.reply(&data.prefix(component.guild_id)))
+                .ephemeral(true);
+            component.create_followup(ctx, followup).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Someone clicked a button on the now-playing message. The same permissions
+/// apply as for playing quips, and replays count against the cooldowns.
+async fn control_playback(
+    ctx: &serenity::Context,
+    data: &Data,
+    component: &serenity::ComponentInteraction,
+    control: nowplaying::Control,
+) -> Result<(), Error> {
+    let member = component
+        .member
+        .as_ref()
+        .ok_or("This only works in a server.")?;
+    let guild_id = member.guild_id;
+    let admin = {
+        let guild = ctx.cache.guild(guild_id);
+        member_is_admin(&data.config, guild.as_deref(), member)
+    };
+    let access = perms::check(
+        data.config.guild(guild_id),
+        Some(perms::QUIPS),
+        component.channel_id,
+        &member.roles,
+        admin,
+    );
+    match access {
+        Access::Allowed => {}
+        Access::Denied(reason) => return Err(reason.into()),
+        Access::Ignored => return Err("I don't take commands in this channel.".into()),
+    }
+
+    let manager = voice_manager(ctx).await?;
+    let handler_lock = manager.get(guild_id).ok_or("I'm not in a voice channel.")?;
+    match control {
+        // Every quip replaces whatever was playing, so nothing is ever
+        // queued and skipping is the same as stopping.
+        nowplaying::Control::Stop | nowplaying::Control::Skip => {
+            handler_lock.lock().await.stop();
+        }
+        nowplaying::Control::Replay => {
+            let playing = data
+                .now_playing
+                .current(guild_id)
+                .ok_or("Nothing has been played yet.")?;
+            let library = data.library();
+            let (idx, dir_entry) = library
+                .find_by_id(&playing.quip)
+                .ok_or("That quip no longer exists.")?;
+            // Whoever played it first may have been allowed to play it in
+            // full, which says nothing about whoever replays it.
+            let cutoff = match data.config.playback(guild_id).limit() {
+                Some(limit) if library.is_long(&playing.quip, Some(limit)) => {
+                    if !perms::may_play_long(data.config.guild(guild_id), &member.roles, admin) {
+                        return Err(format!(
+                            "{} Play it with \"--long\" instead.",
+                            over_limit(&library, &playing.quip, limit)
+                        )
+                        .into());
+                    }
+                    None
+                }
+                Some(limit) => Some(Duration::from_secs_f64(limit)),
+                None => None,
+            };
+            if !admin {
+                check_cooldown(data, guild_id, component.channel_id, member.user.id)?;
+            }
+            let track = start_track(
+                ctx,
+                data,
+                guild_id,
+                component.channel_id,
+                dir_entry,
+                idx + 1,
+                cutoff,
+            )
+            .await?;
+            let quip = playing.quip.clone();
+            let replay = nowplaying::Playing {
+                number: idx + 1,
+                cutoff,
+                requester: member.user.id,
+                position: Duration::ZERO,
+                finished: false,
+                ..playing
+            };
+            show_now_playing(ctx, data, guild_id, component.channel_id, &track, replay).await?;
+            record_play(data, guild_id, member.user.id, quip);
+        }
+    }
+    Ok(())
+}
+
+/// A moderator clicked "Approve" or "Reject" on a submission. On success,
+/// the review message is updated and the submitter notified; otherwise only
+/// the moderator is told why.
+async fn review_submission(
+    ctx: &serenity::Context,
+    data: &Data,
+    component: &serenity::ComponentInteraction,
+    decision: Status,
+    id: i64,
+) -> Result<(), Error> {
+    let admin = component.member.as_ref().is_some_and(|member| {
+        let guild = ctx.cache.guild(member.guild_id);
+        member_is_admin(&data.config, guild.as_deref(), member)
+    });
+    let decided = if admin {
+        decide_submission(data, decision, id, component.user.id).await
+    } else {
+        Err("Only bot admins can review submitted quips.".into())
+    };
+
+    match decided {
+        Ok((submission, notice)) => {
+            let status = format!(
+                "{} by {}.",
+                if decision == Status::Approved {
+                    "Approved"
+                } else {
+                    "Rejected"
+                },
+                component.user.id.mention()
+            );
+            let response = serenity::CreateInteractionResponseMessage::new()
+                .content(status)
+                .components(Vec::new());
+            component
+                .create_response(
+                    ctx,
+                    serenity::CreateInteractionResponse::UpdateMessage(response),
+                )
+                .await?;
+            submission.channel_id.say(ctx, notice).await?;
+        }
+        Err(reason) => {
+            let response = serenity::CreateInteractionResponseMessage::new()
+                .content(reason.reply(&data.prefix(component.guild_id)))
+                .ephemeral(true);
+            component
+                .create_response(ctx, serenity::CreateInteractionResponse::Message(response))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Carry out a decision on a submission: move the quip into its category or
+/// throw it away. Returns the submission and a notice for the submitter.
+async fn decide_submission(
+    data: &Data,
+    decision: Status,
+    id: i64,
+    moderator: serenity::UserId,
+) -> Result<(Submission, String), Error> {
+    let submission = data
+        .store
+        .submission(id)?
+        .ok_or("This submission no longer exists.")?;
+    if submission.status != Status::Pending {
+        return Err(format!("This quip has already been {}.", submission.status.as_str()).into());
+    }
+    let library = data.library();
+    let pending_path = submission.pending_path(&library.top_dir);
+
+    if decision == Status::Rejected {
+        if !data
+            .store
+            .decide_submission(id, decision, moderator, stats::now())?
+        {
+            return Err("Another moderator has already reviewed this quip.".into());
+        }
+        if let Err(e) = std::fs::remove_file(&pending_path) {
+            warn!(path = %pending_path.display(), error = %e, "Failed to remove rejected quip");
+        }
+        let notice = format!(
+            "{}, sorry, your quip \"{}\" for category `{}` was not accepted.",
+            submission.user_id.mention(),
+            submission.file_name,
+            submission.category
+        );
+        return Ok((submission, notice));
+    }
+
+    // The library may have changed since the quip was submitted.
+    let (category, library) = data
+        .edit_library(|library| {
+            let category = library.resolve_category(&submission.category)?.clone();
+            let stem = Path::new(&submission.file_name)
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy();
+            if library.has_stem(&category, &stem) {
+                return Err(format!(
+                    "Category `{}` already has a quip named \"{}\". Rename or remove it first.",
+                    category, stem
+                )
+                .into());
+            }
+            let path = library.top_dir.join(&category).join(&submission.file_name);
+            // Move the file first: if that fails, the submission stays pending
+            // and can be approved again. If it can't be marked decided, the file
+            // goes back to wait for whoever did decide it.
+            std::fs::rename(&pending_path, &path)?;
+            let decided = data
+                .store
+                .decide_submission(id, decision, moderator, stats::now());
+            if !matches!(decided, Ok(true))
+                && let Err(e) = std::fs::rename(&path, &pending_path)
+            {
+                warn!(path = %path.display(), error = %e, "Failed to move quip back to pending");
+            }
+            if !decided? {
+                return Err("Another moderator has already reviewed this quip.".into());
+            }
+            Ok(category)
+        })
+        .await?;
+    let quip = (category, submission.file_name.clone());
+    let notice = format!(
+        "{}, your quip \"{} {}\" ({}) was approved!",
+        submission.user_id.mention(),
+        quip.0,
+        quip_number(&library, &quip),
+        quip.1
+    );
+    Ok((submission, notice))
+}
+
 /// Draw random leaders: "!civ_draft n_players n_leaders."
 ///
 /// Example: `!civ_draft 4 5` to draw five leaders each for four players.
 ///
 /// There will be no duplicate leaders or civilizations.
-#[poise::command(prefix_command)]
+#[poise::command(prefix_command, category = "Civ")]
 async fn civ_draft(ctx: Context<'_>, n_players: usize, n_leaders: usize) -> Result<(), Error> {
     // Draw leaders.
     let leaders = draw_leaders(n_players * n_leaders);
@@ -365,7 +2249,7 @@ async fn civ_draft(ctx: Context<'_>, n_players: usize, n_leaders: usize) -> Resu
 }
 
 /// List game modes. Useful in conjunction with "!civ_draw_modes"
-#[poise::command(prefix_command)]
+#[poise::command(prefix_command, category = "Civ")]
 async fn civ_list_modes(ctx: Context<'_>) -> Result<(), Error> {
     let mut to_say = String::new();
     for (idx, mode) in GAME_MODES.iter().enumerate() {
@@ -389,7 +2273,7 @@ async fn civ_list_modes(ctx: Context<'_>) -> Result<(), Error> {
 ///
 ///     n: Number of modes to draw. Must be set if using "exclude." Set to 0 (or don't set) for a random number of modes.
 ///     exclude: Space separated integers for modes to include. Use `!civ_list_modes` to get the mapping of integers to modes.
-#[poise::command(prefix_command)]
+#[poise::command(prefix_command, category = "Civ")]
 async fn civ_draw_modes(
     ctx: Context<'_>,
     n: Option<usize>,
@@ -446,7 +2330,7 @@ async fn civ_draw_modes(
 }
 
 /// Draw a single random map.
-#[poise::command(prefix_command)]
+#[poise::command(prefix_command, category = "Civ")]
 async fn civ_draw_map(ctx: Context<'_>) -> Result<(), Error> {
     let map = draw_map();
     ctx.say(map).await?;
@@ -454,7 +2338,7 @@ async fn civ_draw_map(ctx: Context<'_>) -> Result<(), Error> {
 }
 
 /// Draw random game settings to jump-start Civilization VI game setup.
-#[poise::command(prefix_command)]
+#[poise::command(prefix_command, category = "Civ")]
 async fn civ_draw_settings(ctx: Context<'_>) -> Result<(), Error> {
     ctx.say(draw_settings()).await?;
     Ok(())
@@ -506,8 +2390,114 @@ async fn dice(ctx: Context<'_>, n_sides: u32, n_dice: Option<usize>) -> Result<(
     Ok(())
 }
 
+/// Error handler which answers unknown commands with "did you mean" suggestions,
+/// replies to failed commands according to the kind of error (see Error), and
+/// otherwise defers to poise's built-in handler.
+async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
+    match error {
+        poise::FrameworkError::UnknownCommand {
+            ctx,
+            msg,
+            prefix,
+            msg_content,
+            framework,
+            ..
+        } => {
+            // Respect the channel restrictions, like for known commands.
+            let guild_config = msg
+                .guild_id
+                .and_then(|g| framework.user_data.config.guild(g));
+            if !perms::listens_in(guild_config, msg.channel_id) {
+                return;
+            }
+
+            let name = msg_content.split_whitespace().next().unwrap_or_default();
+            let mut candidates: Vec<&str> = Vec::new();
+            for command in framework.options().commands.iter() {
+                if !command.hide_in_help {
+                    candidates.push(&command.name);
+                }
+                candidates.extend(command.aliases.iter().map(|a| a.as_str()));
+            }
+            let suggestions = closest(name, candidates);
+
+            // Stay quiet if nothing is close: the message may well have been
+            // meant for another bot sharing the same prefix.
+            if suggestions.is_empty() {
+                return;
+            }
+            let prefixed: Vec<String> = suggestions
+                .iter()
+                .map(|s| format!("{}{}", prefix, s))
+                .collect();
+            let prefixed: Vec<&str> = prefixed.iter().map(|s| s.as_str()).collect();
+            let reply = format!(
+                "Unknown command `{}{}`{} Type \"{}help\" for a list of commands.",
+                prefix,
+                name,
+                did_you_mean(&prefixed),
+                prefix
+            );
+            if let Err(e) = msg.channel_id.say(ctx, reply).await {
+                error!(error = %e, "Failed to send unknown command reply");
+            }
+        }
+        // Checks explain themselves through their error, if they have one.
+        poise::FrameworkError::CommandCheckFailed {
+            ctx,
+            error: Some(error),
+            ..
+        } => {
+            if let Err(e) = ctx.say(error.reply(ctx.prefix())).await {
+                error!(error = %e, "Failed to send command check reply");
+            }
+        }
+        poise::FrameworkError::Command { error, ctx, .. } => {
+            time_command(ctx).await;
+            let command = &ctx.command().qualified_name;
+            let guild_id = ctx.guild_id();
+            let user_id = ctx.author().id;
+            if error.should_log() {
+                error!(command, ?guild_id, %user_id, %error, "Command failed");
+            } else {
+                debug!(command, ?guild_id, %user_id, %error, "Command refused");
+            }
+            if let Err(e) = ctx.say(error.reply(ctx.prefix())).await {
+                error!(error = %e, "Failed to send command error reply");
+            }
+        }
+        // Bugs shouldn't happen, but if they do, the bot carries on.
+        poise::FrameworkError::CommandPanic { ctx, payload, .. } => {
+            error!(
+                command = ctx.command().qualified_name,
+                guild_id = ?ctx.guild_id(),
+                user_id = %ctx.author().id,
+                payload = payload.as_deref().unwrap_or("unknown cause"),
+                "Command panicked"
+            );
+            let reply = Error::Internal("panic".into()).reply(ctx.prefix());
+            if let Err(e) = ctx.say(reply).await {
+                error!(error = %e, "Failed to send command panic reply");
+            }
+        }
+        error => {
+            if let Err(e) = poise::builtins::on_error(error).await {
+                error!(error = %e, "Error while handling error");
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Set the bot up and run it until the connection to Discord ends for good.
+async fn run() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
 
     let top_dir = if args.len() < 2 {
@@ -515,44 +2505,129 @@ async fn main() {
     } else if args.len() == 2 {
         args[1].to_string()
     } else {
-        panic!("Provide a single argument, the path to the directory containing audio files.")
+        return Err(
+            "Provide a single argument, the path to the directory containing audio files.".into(),
+        );
+    };
+
+    let token = std::env::var("DISCORD_TOKEN").map_err(|_| "missing DISCORD_TOKEN")?;
+
+    // Optional configuration file, see config.rs for the available settings.
+    let config_path = env::var("DISQUIP_CONFIG").unwrap_or_else(|_| String::from("config.toml"));
+    let config = Config::load(Path::new(&config_path))?;
+    // Logs written to file are flushed when this is dropped, on the way out.
+    let _log_guard = logging::init(&config.logging)?;
+
+    // The database lives next to the audio directory unless configured otherwise.
+    let db_path = match &config.database {
+        Some(path) => path.clone(),
+        None => Path::new(&top_dir)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join("disquip.sqlite3"),
     };
+    let store = SqliteStore::open(&db_path)?;
 
-    let token = std::env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN");
+    // Created up front so the monitoring endpoints can see the voice calls.
+    let voice = songbird::Songbird::serenity();
+    // The health endpoint starts first, to tell starting up from stuck.
+    let status = Arc::new(health::Status::new(Duration::from_secs(
+        config.health.gateway_grace,
+    )));
+    let watch_gateway = config.health.enabled;
+    if config.health.enabled {
+        let listener = http::bind(config.health.listen).await?;
+        info!(address = %config.health.listen, "Serving health checks");
+        let status = status.clone();
+        let voice = voice.clone();
+        tokio::spawn(http::serve(listener, move |path| {
+            let status = status.clone();
+            let voice = voice.clone();
+            async move {
+                let report = status.report(voice_connections(&voice).await).await;
+                health::respond(&path, &report)
+            }
+        }));
+    }
 
-    let data = Data::new(top_dir);
+    let data = Data::new(top_dir, config, Arc::new(store))?;
+    status.library_loaded();
+
+    if data.config.metrics.enabled {
+        let listener = http::bind(data.config.metrics.listen).await?;
+        info!(address = %data.config.metrics.listen, "Serving metrics");
+        let metrics = data.metrics.clone();
+        let voice = voice.clone();
+        tokio::spawn(http::serve(listener, move |path| {
+            let metrics = metrics.clone();
+            let voice = voice.clone();
+            async move {
+                match path.as_str() {
+                    "/metrics" => http::Response::ok(
+                        metrics::CONTENT_TYPE,
+                        metrics.render(voice_connections(&voice).await),
+                    ),
+                    _ => http::Response::not_found(),
+                }
+            }
+        }));
+    }
 
     let intents = serenity::GatewayIntents::non_privileged()
         | serenity::GatewayIntents::GUILD_MESSAGES
         | serenity::GatewayIntents::MESSAGE_CONTENT
         | serenity::GatewayIntents::GUILD_VOICE_STATES;
 
+    // No static prefix: every guild may have its own, see dynamic_prefix.
     let prefix_framework_options = poise::PrefixFrameworkOptions {
-        prefix: Some("!".to_string()),
+        dynamic_prefix: Some(|ctx| Box::pin(dynamic_prefix(ctx))),
+        mention_as_prefix: data.config.mention_prefix,
+        case_insensitive_commands: data.config.case_insensitive_commands,
         ..Default::default()
     };
 
     let mut command = join_and_play();
-    let mut keys: Vec<_> = data.file_map.keys().cloned().collect();
+    let mut keys: Vec<_> = data.library().file_map.keys().cloned().collect();
     keys.sort();
-    command.aliases = data.file_map.keys().cloned().collect();
+    command.aliases = keys;
 
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
             prefix_options: prefix_framework_options,
+            // The help menu groups commands by category in the order they
+            // first appear here.
             commands: vec![
                 list(),
                 random(),
-                disconnect(),
-                dice(),
+                get(),
+                info(),
+                again(),
+                history(),
+                stats(),
+                fav(),
+                tags(),
+                tag(),
+                upload(),
                 civ_draft(),
                 civ_list_modes(),
                 civ_draw_modes(),
                 civ_draw_map(),
                 civ_draw_settings(),
+                dice(),
                 help(),
+                prefix(),
+                disconnect(),
+                quip(),
+                reload(),
                 command,
             ],
+            on_error: |error| Box::pin(on_error(error)),
+            event_handler: |ctx, event, framework, data| {
+                Box::pin(event_handler(ctx, event, framework, data))
+            },
+            command_check: Some(|ctx| Box::pin(command_check(ctx))),
+            pre_command: |ctx| Box::pin(log_command(ctx)),
+            post_command: |ctx| Box::pin(time_command(ctx)),
             ..Default::default()
         })
         .setup(|ctx, _ready, framework| {
@@ -563,9 +2638,14 @@ async fn main() {
         })
         .build();
 
-    let client = serenity::ClientBuilder::new(token, intents)
+    let mut client = serenity::ClientBuilder::new(token, intents)
         .framework(framework)
-        .register_songbird()
-        .await;
-    client.unwrap().start().await.unwrap();
+        .register_songbird_with(voice)
+        .await?;
+    status.client_built(client.shard_manager.clone(), client.cache.clone());
+    if watch_gateway {
+        tokio::spawn(async move { status.watch_gateway().await });
+    }
+    client.start().await?;
+    Ok(())
 }
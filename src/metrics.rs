@@ -0,0 +1,279 @@
+use crate::library::Library;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Content type of the Prometheus text format, see Metrics::render.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the command latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Counters and gauges for the metrics endpoint. Everything is counted since
+/// the bot started; Prometheus works out rates from that.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    commands: BTreeMap<String, u64>,
+    latency: BTreeMap<String, Histogram>,
+    // Plays by category.
+    plays: BTreeMap<String, u64>,
+    playback_errors: u64,
+    quips: usize,
+    categories: usize,
+    // Quip details reused from the previous library scan, or analyzed.
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Observations per bucket, not cumulative: render adds them up.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|&le| value <= le) {
+            self.buckets[idx] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Metrics {
+    /// Count a command about to run.
+    pub fn command(&self, name: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        *inner.commands.entry(name.to_string()).or_default() += 1;
+    }
+
+    /// Record how long a command took, whether it succeeded or not.
+    pub fn command_finished(&self, name: &str, elapsed: Duration) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner
+            .latency
+            .entry(name.to_string())
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count a quip played.
+    pub fn played(&self, category: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        *inner.plays.entry(category.to_string()).or_default() += 1;
+    }
+
+    /// Count a track that failed to play.
+    pub fn playback_error(&self) {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .playback_errors += 1;
+    }
+
+    /// Take the size of a freshly scanned library, and how well the scan
+    /// could reuse the previous one.
+    pub fn library_scanned(&self, library: &Library) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.quips = library.file_map.values().map(|v| v.len()).sum();
+        inner.categories = library.file_map.len();
+        inner.cache_hits += library.reused as u64;
+        inner.cache_misses += (inner.quips - library.reused) as u64;
+    }
+
+    /// The metrics in the Prometheus text format. Voice connections come and
+    /// go without the bot's say (e.g. when it's kicked), so they're counted
+    /// by the caller when asked for.
+    pub fn render(&self, voice_connections: usize) -> String {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "disquip_commands_total",
+            "counter",
+            "Commands run, by command.",
+        );
+        for (command, count) in &inner.commands {
+            sample(
+                &mut out,
+                "disquip_commands_total",
+                &[("command", command)],
+                *count,
+            );
+        }
+
+        header(
+            &mut out,
+            "disquip_command_duration_seconds",
+            "histogram",
+            "How long commands took to run, by command.",
+        );
+        for (command, histogram) in &inner.latency {
+            let mut cumulative = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                sample(
+                    &mut out,
+                    "disquip_command_duration_seconds_bucket",
+                    &[("command", command), ("le", &le.to_string())],
+                    cumulative,
+                );
+            }
+            let labels = [("command", command.as_str())];
+            sample(
+                &mut out,
+                "disquip_command_duration_seconds_bucket",
+                &[("command", command), ("le", "+Inf")],
+                histogram.count,
+            );
+            sample(
+                &mut out,
+                "disquip_command_duration_seconds_sum",
+                &labels,
+                histogram.sum,
+            );
+            sample(
+                &mut out,
+                "disquip_command_duration_seconds_count",
+                &labels,
+                histogram.count,
+            );
+        }
+
+        header(
+            &mut out,
+            "disquip_quips_played_total",
+            "counter",
+            "Quips played, by category.",
+        );
+        for (category, count) in &inner.plays {
+            sample(
+                &mut out,
+                "disquip_quips_played_total",
+                &[("category", category)],
+                *count,
+            );
+        }
+
+        let single: [(&str, &str, &str, f64); 6] = [
+            (
+                "disquip_playback_errors_total",
+                "counter",
+                "Quips that failed to play.",
+                inner.playback_errors as f64,
+            ),
+            (
+                "disquip_voice_connections",
+                "gauge",
+                "Voice channels the bot is connected to.",
+                voice_connections as f64,
+            ),
+            (
+                "disquip_library_quips",
+                "gauge",
+                "Quips in the library.",
+                inner.quips as f64,
+            ),
+            (
+                "disquip_library_categories",
+                "gauge",
+                "Quip categories in the library.",
+                inner.categories as f64,
+            ),
+            (
+                "disquip_library_cache_hits_total",
+                "counter",
+                "Quips whose details were reused from the previous library scan.",
+                inner.cache_hits as f64,
+            ),
+            (
+                "disquip_library_cache_misses_total",
+                "counter",
+                "Quips that were analyzed during a library scan.",
+                inner.cache_misses as f64,
+            ),
+        ];
+        for (name, kind, help, value) in single {
+            header(&mut out, name, kind, help);
+            sample(&mut out, name, &[], value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+/// Escape a label value: category names are directory names, which may
+/// contain anything.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.command("list");
+        metrics.command("list");
+        metrics.command_finished("list", Duration::from_millis(20));
+        metrics.command_finished("list", Duration::from_secs(20));
+        metrics.played("a\"1");
+        metrics.playback_error();
+        let out = metrics.render(2);
+
+        assert!(out.contains("# TYPE disquip_commands_total counter\n"));
+        assert!(out.contains("disquip_commands_total{command=\"list\"} 2\n"));
+        assert!(
+            out.contains(
+                "disquip_command_duration_seconds_bucket{command=\"list\",le=\"0.01\"} 0\n"
+            )
+        );
+        assert!(out.contains(
+            "disquip_command_duration_seconds_bucket{command=\"list\",le=\"0.025\"} 1\n"
+        ));
+        assert!(
+            out.contains("disquip_command_duration_seconds_bucket{command=\"list\",le=\"10\"} 1\n")
+        );
+        assert!(
+            out.contains(
+                "disquip_command_duration_seconds_bucket{command=\"list\",le=\"+Inf\"} 2\n"
+            )
+        );
+        assert!(out.contains("disquip_command_duration_seconds_count{command=\"list\"} 2\n"));
+        assert!(out.contains("disquip_quips_played_total{category=\"a\\\"1\"} 1\n"));
+        assert!(out.contains("disquip_playback_errors_total 1\n"));
+        assert!(out.contains("disquip_voice_connections 2\n"));
+        assert!(out.contains("disquip_library_quips 0\n"));
+    }
+}
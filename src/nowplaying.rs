@@ -0,0 +1,359 @@
+use crate::QuipId;
+use crate::audio;
+use poise::serenity_prelude::{
+    self as serenity, ChannelId, GuildId, Mentionable, MessageId, UserId,
+};
+use songbird::events::{Event, EventContext, EventHandler};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// How often the progress on the now-playing message is updated, in track
+/// time. Discord rate limits message edits, so not too often.
+pub const UPDATE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Width of the progress bar, in characters.
+const BAR_WIDTH: usize = 12;
+
+/// A button on the now-playing message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    Stop,
+    Skip,
+    Replay,
+}
+
+impl Control {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Control::Stop => "stop",
+            Control::Skip => "skip",
+            Control::Replay => "replay",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Control> {
+        match s {
+            "stop" => Some(Control::Stop),
+            "skip" => Some(Control::Skip),
+            "replay" => Some(Control::Replay),
+            _ => None,
+        }
+    }
+}
+
+/// Custom ID of a now-playing button. Each guild has a single now-playing
+/// message, so the guild the button is clicked in says which one it is.
+pub fn button_id(control: Control) -> String {
+    format!("nowplaying:{}", control.as_str())
+}
+
+/// Parse the custom ID of a now-playing button, see button_id.
+pub fn parse_button_id(custom_id: &str) -> Option<Control> {
+    Control::parse(custom_id.strip_prefix("nowplaying:")?)
+}
+
+/// What the now-playing message shows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Playing {
+    pub quip: QuipId,
+    /// The quip's 1-based number in its category.
+    pub number: usize,
+    pub requester: UserId,
+    /// How long the quip plays for, if known: its duration, or the cutoff if
+    /// that comes first.
+    pub length: Option<Duration>,
+    /// Where the quip gets cut off, if it does, so a replay does the same.
+    pub cutoff: Option<Duration>,
+    pub transcript: Option<String>,
+    pub position: Duration,
+    pub finished: bool,
+}
+
+/// The now-playing message of each guild and what it shows.
+#[derive(Debug, Default)]
+pub struct Board {
+    guilds: Mutex<HashMap<GuildId, Slot>>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    message: Option<(ChannelId, MessageId)>,
+    playing: Playing,
+    /// Changes with every quip played, so updates from tracks that have
+    /// since been replaced are ignored.
+    play_id: u64,
+}
+
+impl Board {
+    /// Start showing a new quip. Returns the ID to update it with, and the
+    /// guild's previous message if it was in another channel, which should be
+    /// cleaned up since the new one is posted where the quip was asked for.
+    pub fn start(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        playing: Playing,
+    ) -> (u64, Option<(ChannelId, MessageId)>) {
+        let mut guilds = self.guilds.lock().unwrap_or_else(PoisonError::into_inner);
+        let (play_id, message) = match guilds.remove(&guild_id) {
+            Some(slot) => (slot.play_id + 1, slot.message),
+            None => (0, None),
+        };
+        let (message, stale) = match message {
+            Some((channel, _)) if channel == channel_id => (message, None),
+            other => (None, other),
+        };
+        guilds.insert(
+            guild_id,
+            Slot {
+                message,
+                playing,
+                play_id,
+            },
+        );
+        (play_id, stale)
+    }
+
+    /// Change what's shown, if the quip is still the one playing. Returns the
+    /// message to edit, if any, and what to show on it.
+    pub fn update(
+        &self,
+        guild_id: GuildId,
+        play_id: u64,
+        change: impl FnOnce(&mut Playing),
+    ) -> Option<(Option<(ChannelId, MessageId)>, Playing)> {
+        let mut guilds = self.guilds.lock().unwrap_or_else(PoisonError::into_inner);
+        let slot = guilds.get_mut(&guild_id).filter(|s| s.play_id == play_id)?;
+        change(&mut slot.playing);
+        Some((slot.message, slot.playing.clone()))
+    }
+
+    /// Remember the message posted for a guild. Even if another quip started
+    /// meanwhile, this is the message to edit from now on.
+    pub fn set_message(&self, guild_id: GuildId, message: (ChannelId, MessageId)) {
+        let mut guilds = self.guilds.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(slot) = guilds.get_mut(&guild_id) {
+            slot.message = Some(message);
+        }
+    }
+
+    /// The quip last shown in a guild.
+    pub fn current(&self, guild_id: GuildId) -> Option<Playing> {
+        let guilds = self.guilds.lock().unwrap_or_else(PoisonError::into_inner);
+        guilds.get(&guild_id).map(|s| s.playing.clone())
+    }
+}
+
+/// The text of the now-playing message.
+pub fn describe(playing: &Playing) -> String {
+    let (category, file_name) = &playing.quip;
+    let mut text = format!(
+        "**{} {}** ({})\nRequested by {}\n{}",
+        category,
+        playing.number,
+        file_name,
+        playing.requester.mention(),
+        progress(playing.position, playing.length, playing.finished)
+    );
+    if let Some(transcript) = &playing.transcript {
+        text.push('\n');
+        text.push_str(&audio::quote_transcript(
+            transcript,
+            audio::MAX_TRANSCRIPT_CHARS,
+        ));
+    }
+    text
+}
+
+/// A progress bar with the time played, e.g. "▰▰▰▱▱▱ 0:03 / 0:06".
+pub fn progress(position: Duration, length: Option<Duration>, finished: bool) -> String {
+    let Some(length) = length else {
+        return format_time(position);
+    };
+    let position = if finished {
+        length
+    } else {
+        position.min(length)
+    };
+    let filled = if length.is_zero() {
+        BAR_WIDTH
+    } else {
+        (BAR_WIDTH as f64 * position.as_secs_f64() / length.as_secs_f64()).round() as usize
+    };
+    format!(
+        "{}{} {} / {}",
+        "▰".repeat(filled),
+        "▱".repeat(BAR_WIDTH - filled),
+        format_time(position),
+        format_time(length)
+    )
+}
+
+/// A duration as minutes and seconds, e.g. "1:05".
+fn format_time(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// The now-playing message, embed and buttons.
+pub fn message(playing: &Playing) -> (serenity::CreateEmbed, serenity::CreateActionRow) {
+    let embed = serenity::CreateEmbed::new()
+        .title(if playing.finished {
+            "Finished playing"
+        } else {
+            "Now playing"
+        })
+        .description(describe(playing));
+    let buttons = serenity::CreateActionRow::Buttons(vec![
+        serenity::CreateButton::new(button_id(Control::Stop))
+            .label("Stop")
+            .style(serenity::ButtonStyle::Danger)
+            .disabled(playing.finished),
+        serenity::CreateButton::new(button_id(Control::Skip))
+            .label("Skip")
+            .style(serenity::ButtonStyle::Secondary)
+            .disabled(playing.finished),
+        serenity::CreateButton::new(button_id(Control::Replay))
+            .label("Replay")
+            .style(serenity::ButtonStyle::Primary),
+    ]);
+    (embed, buttons)
+}
+
+/// Edit a now-playing message to show the given quip.
+pub async fn edit(
+    http: &serenity::Http,
+    (channel_id, message_id): (ChannelId, MessageId),
+    playing: &Playing,
+) -> Result<(), serenity::Error> {
+    let (embed, buttons) = message(playing);
+    let edit = serenity::EditMessage::new()
+        .embed(embed)
+        .components(vec![buttons]);
+    channel_id.edit_message(http, message_id, edit).await?;
+    Ok(())
+}
+
+/// Keeps a guild's now-playing message up to date from songbird events on
+/// the track playing: periodically while it plays, and when it ends or fails.
+#[derive(Clone)]
+pub struct Updater {
+    pub board: Arc<Board>,
+    pub http: Arc<serenity::Http>,
+    pub guild_id: GuildId,
+    pub play_id: u64,
+}
+
+#[serenity::async_trait]
+impl EventHandler for Updater {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let EventContext::Track(track_list) = ctx else {
+            return None;
+        };
+        let (state, _) = track_list.first()?;
+        let updated = self.board.update(self.guild_id, self.play_id, |playing| {
+            playing.position = state.position;
+            playing.finished |= state.playing.is_done();
+        });
+        if let Some((Some(message), playing)) = updated
+            && let Err(e) = edit(&self.http, message, &playing).await
+        {
+            tracing::warn!(guild_id = %self.guild_id, error = %e, "Failed to update now-playing message");
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing() -> Playing {
+        Playing {
+            quip: ("a1".to_string(), "Hmm.mp3".to_string()),
+            number: 3,
+            requester: UserId::new(7),
+            length: Some(Duration::from_secs(6)),
+            cutoff: None,
+            transcript: None,
+            position: Duration::ZERO,
+            finished: false,
+        }
+    }
+
+    #[test]
+    fn test_button_id() {
+        for control in [Control::Stop, Control::Skip, Control::Replay] {
+            assert_eq!(parse_button_id(&button_id(control)), Some(control));
+        }
+        assert_eq!(parse_button_id("nowplaying:pause"), None);
+        assert_eq!(parse_button_id("review:approved:1"), None);
+    }
+
+    #[test]
+    fn test_progress() {
+        let secs = Duration::from_secs;
+        assert_eq!(
+            progress(secs(3), Some(secs(6)), false),
+            "▰▰▰▰▰▰▱▱▱▱▱▱ 0:03 / 0:06"
+        );
+        assert_eq!(
+            progress(secs(1), Some(secs(6)), true),
+            "▰▰▰▰▰▰▰▰▰▰▰▰ 0:06 / 0:06"
+        );
+        assert_eq!(
+            progress(secs(65), Some(secs(6)), false),
+            "▰▰▰▰▰▰▰▰▰▰▰▰ 0:06 / 0:06"
+        );
+        assert_eq!(progress(secs(65), None, false), "1:05");
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut playing = playing();
+        assert_eq!(
+            describe(&playing),
+            "**a1 3** (Hmm.mp3)\nRequested by <@7>\n▱▱▱▱▱▱▱▱▱▱▱▱ 0:00 / 0:06"
+        );
+        playing.transcript = Some("Hmm?".to_string());
+        assert!(describe(&playing).ends_with("\n> Hmm?"));
+    }
+
+    #[test]
+    fn test_board() {
+        let board = Board::default();
+        let guild_id = GuildId::new(1);
+        let channel = ChannelId::new(10);
+        let message = (channel, MessageId::new(100));
+
+        let (first, stale) = board.start(guild_id, channel, playing());
+        assert_eq!(stale, None);
+        board.set_message(guild_id, message);
+        let (to_edit, shown) = board
+            .update(guild_id, first, |p| p.position = Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(to_edit, Some(message));
+        assert_eq!(shown.position, Duration::from_secs(2));
+
+        // A new quip in the same channel edits the same message, and updates
+        // for the old one are ignored.
+        let (second, stale) = board.start(guild_id, channel, playing());
+        assert_eq!(stale, None);
+        assert!(
+            board
+                .update(guild_id, first, |p| p.finished = true)
+                .is_none()
+        );
+        assert!(!board.current(guild_id).unwrap().finished);
+        assert_eq!(
+            board.update(guild_id, second, |_| ()).unwrap().0,
+            Some(message)
+        );
+
+        // Elsewhere, the old message is handed back to be cleaned up.
+        let (third, stale) = board.start(guild_id, ChannelId::new(11), playing());
+        assert_eq!(stale, Some(message));
+        assert_eq!(board.update(guild_id, third, |_| ()).unwrap().0, None);
+    }
+}
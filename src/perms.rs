@@ -0,0 +1,179 @@
+use crate::config::GuildConfig;
+use poise::serenity_prelude::{ChannelId, RoleId};
+
+// Command categories, as given to `#[poise::command(category = ...)]`. They
+// double as the headings in the help menu.
+pub const QUIPS: &str = "Quips";
+pub const CIV: &str = "Civ";
+pub const ADMIN: &str = "Admin";
+
+/// Outcome of a permission check for a single command invocation.
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    Allowed,
+    /// Not allowed, with an explanation for the user.
+    Denied(String),
+    /// The bot doesn't listen in this channel, so say nothing at all.
+    Ignored,
+}
+
+/// Decide whether a member may run a command of the given category in the
+/// given channel. Without any configuration for the guild, everything except
+/// admin commands is open to everyone. Admins may do anything, anywhere.
+pub fn check(
+    guild: Option<&GuildConfig>,
+    category: Option<&str>,
+    channel: ChannelId,
+    roles: &[RoleId],
+    is_admin: bool,
+) -> Access {
+    if is_admin {
+        return Access::Allowed;
+    }
+    if !listens_in(guild, channel) {
+        return Access::Ignored;
+    }
+    if category == Some(ADMIN) {
+        return Access::Denied("Only bot admins may use this command.".to_string());
+    }
+    let Some(guild) = guild else {
+        return Access::Allowed;
+    };
+
+    let (allowed_roles, what) = match category {
+        Some(QUIPS) => (&guild.quip_roles, "play quips"),
+        Some(CIV) => (&guild.civ_roles, "use Civ commands"),
+        _ => return Access::Allowed,
+    };
+    if allowed_roles.is_empty() || allowed_roles.iter().any(|r| roles.contains(r)) {
+        Access::Allowed
+    } else {
+        Access::Denied(format!(
+            "Sorry, you don't have a role that is allowed to {}.",
+            what
+        ))
+    }
+}
+
+/// Whether a member may add quips with "!upload". Unlike the other commands,
+/// this is closed to everyone but admins unless upload roles are configured.
+pub fn may_upload(guild: Option<&GuildConfig>, roles: &[RoleId], is_admin: bool) -> bool {
+    is_admin || guild.is_some_and(|g| g.upload_roles.iter().any(|r| roles.contains(r)))
+}
+
+/// Whether a member may play quips over the playback limit without asking for
+/// them with "--long".
+pub fn may_play_long(guild: Option<&GuildConfig>, roles: &[RoleId], is_admin: bool) -> bool {
+    is_admin || guild.is_some_and(|g| g.long_roles.iter().any(|r| roles.contains(r)))
+}
+
+/// Whether the bot listens to messages in the given channel at all.
+pub fn listens_in(guild: Option<&GuildConfig>, channel: ChannelId) -> bool {
+    guild.is_none_or(|g| g.channels.is_empty() || g.channels.contains(&channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guild() -> GuildConfig {
+        GuildConfig {
+            quip_roles: vec![RoleId::new(10)],
+            channels: vec![ChannelId::new(100)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_unconfigured_guild() {
+        let channel = ChannelId::new(1);
+        assert_eq!(
+            check(None, Some(QUIPS), channel, &[], false),
+            Access::Allowed
+        );
+        assert_eq!(check(None, Some(CIV), channel, &[], false), Access::Allowed);
+        assert_eq!(check(None, None, channel, &[], false), Access::Allowed);
+        assert!(matches!(
+            check(None, Some(ADMIN), channel, &[], false),
+            Access::Denied(_)
+        ));
+        assert_eq!(
+            check(None, Some(ADMIN), channel, &[], true),
+            Access::Allowed
+        );
+        assert!(listens_in(None, channel));
+    }
+
+    #[test]
+    fn test_roles() {
+        let guild = guild();
+        let channel = ChannelId::new(100);
+        let member = [RoleId::new(10), RoleId::new(11)];
+        let other = [RoleId::new(11)];
+
+        assert_eq!(
+            check(Some(&guild), Some(QUIPS), channel, &member, false),
+            Access::Allowed
+        );
+        assert!(matches!(
+            check(Some(&guild), Some(QUIPS), channel, &other, false),
+            Access::Denied(_)
+        ));
+        // No civ_roles configured, so anyone can use them.
+        assert_eq!(
+            check(Some(&guild), Some(CIV), channel, &other, false),
+            Access::Allowed
+        );
+        assert!(matches!(
+            check(Some(&guild), Some(ADMIN), channel, &member, false),
+            Access::Denied(_)
+        ));
+    }
+
+    #[test]
+    fn test_channels() {
+        let guild = guild();
+        let member = [RoleId::new(10)];
+        let elsewhere = ChannelId::new(200);
+
+        assert!(listens_in(Some(&guild), ChannelId::new(100)));
+        assert!(!listens_in(Some(&guild), elsewhere));
+        assert_eq!(
+            check(Some(&guild), Some(QUIPS), elsewhere, &member, false),
+            Access::Ignored
+        );
+        assert_eq!(
+            check(Some(&guild), None, elsewhere, &member, false),
+            Access::Ignored
+        );
+        // Admins can use the bot anywhere.
+        assert_eq!(
+            check(Some(&guild), Some(QUIPS), elsewhere, &[], true),
+            Access::Allowed
+        );
+    }
+
+    #[test]
+    fn test_may_upload() {
+        let mut guild = guild();
+        assert!(!may_upload(None, &[], false));
+        assert!(may_upload(None, &[], true));
+        assert!(!may_upload(Some(&guild), &[RoleId::new(10)], false));
+
+        guild.upload_roles = vec![RoleId::new(20)];
+        assert!(may_upload(Some(&guild), &[RoleId::new(20)], false));
+        assert!(!may_upload(Some(&guild), &[RoleId::new(10)], false));
+    }
+
+    #[test]
+    fn test_may_play_long() {
+        let mut guild = guild();
+        assert!(!may_play_long(None, &[], false));
+        assert!(may_play_long(None, &[], true));
+        assert!(!may_play_long(Some(&guild), &[RoleId::new(10)], false));
+
+        guild.long_roles = vec![RoleId::new(30)];
+        assert!(may_play_long(Some(&guild), &[RoleId::new(30)], false));
+        assert!(!may_play_long(Some(&guild), &[RoleId::new(10)], false));
+    }
+}
@@ -0,0 +1,34 @@
+/// Longest prefix a guild may set. Prefixes are typed before every command,
+/// so anything longer than this is almost certainly a mistake.
+pub const MAX_PREFIX_LEN: usize = 5;
+
+/// Check that a prefix is usable, returning a reason if it isn't.
+pub fn validate(prefix: &str) -> Result<(), String> {
+    if prefix.is_empty() {
+        return Err("The prefix cannot be empty.".to_string());
+    }
+    if prefix.chars().any(char::is_whitespace) {
+        return Err("The prefix cannot contain whitespace.".to_string());
+    }
+    if prefix.chars().count() > MAX_PREFIX_LEN {
+        return Err(format!(
+            "The prefix can be at most {} characters long.",
+            MAX_PREFIX_LEN
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("!").is_ok());
+        assert!(validate("?!").is_ok());
+        assert!(validate("").is_err());
+        assert!(validate("a b").is_err());
+        assert!(validate("toolong").is_err());
+    }
+}
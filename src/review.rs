@@ -0,0 +1,107 @@
+use poise::serenity_prelude::{ChannelId, GuildId, UserId};
+use std::path::{Path, PathBuf};
+
+/// Hidden directory in the audio directory where submitted quips wait for
+/// review. Hidden directories aren't scanned for quips.
+pub const PENDING_DIR: &str = ".pending";
+
+/// Where a submission is in the review process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Approved => "approved",
+            Status::Rejected => "rejected",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Status> {
+        match s {
+            "pending" => Some(Status::Pending),
+            "approved" => Some(Status::Approved),
+            "rejected" => Some(Status::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// A quip uploaded by a regular user, waiting for (or past) review.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submission {
+    /// Assigned by the store.
+    pub id: i64,
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    /// Channel the quip was uploaded in, where the submitter is notified.
+    pub channel_id: ChannelId,
+    pub category: String,
+    pub file_name: String,
+    /// Name of the file in the pending directory, see pending_name.
+    pub pending_file: String,
+    pub seconds: f64,
+    pub status: Status,
+    /// Seconds since the Unix epoch.
+    pub submitted_at: i64,
+}
+
+impl Submission {
+    pub fn pending_path(&self, top_dir: &Path) -> PathBuf {
+        top_dir.join(PENDING_DIR).join(&self.pending_file)
+    }
+}
+
+/// Name for a submitted file in the pending directory. Different users may
+/// well submit quips with the same name, so the name alone won't do.
+pub fn pending_name(user_id: UserId, submitted_at: i64, file_name: &str) -> String {
+    format!("{}-{}-{}", user_id, submitted_at, file_name)
+}
+
+/// Custom ID of a review button. The submission ID is in there so buttons keep
+/// working after a restart.
+pub fn button_id(decision: Status, submission_id: i64) -> String {
+    format!("review:{}:{}", decision.as_str(), submission_id)
+}
+
+/// Parse the custom ID of a review button, see button_id.
+pub fn parse_button_id(custom_id: &str) -> Option<(Status, i64)> {
+    let rest = custom_id.strip_prefix("review:")?;
+    let (decision, id) = rest.split_once(':')?;
+    let decision = Status::parse(decision)?;
+    if decision == Status::Pending {
+        return None;
+    }
+    Some((decision, id.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_button_id() {
+        for decision in [Status::Approved, Status::Rejected] {
+            assert_eq!(
+                parse_button_id(&button_id(decision, 42)),
+                Some((decision, 42))
+            );
+        }
+        assert_eq!(parse_button_id("review:pending:42"), None);
+        assert_eq!(parse_button_id("review:approved:x"), None);
+        assert_eq!(parse_button_id("something:else"), None);
+    }
+
+    #[test]
+    fn test_status() {
+        for status in [Status::Pending, Status::Approved, Status::Rejected] {
+            assert_eq!(Status::parse(status.as_str()), Some(status));
+        }
+        assert_eq!(Status::parse("maybe"), None);
+    }
+}
@@ -0,0 +1,296 @@
+use crate::QuipId;
+use crate::library::LONG_FLAG;
+use poise::serenity_prelude::GuildId;
+use rand::Rng;
+use rand::distr::{Distribution, weighted::WeightedIndex};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+
+/// Which quips "!r" may choose from, e.g. "!r a1 a2 -sw" includes the a1 and
+/// a2 categories and excludes sw. No includes means every category. Arguments
+/// starting with '#' are tags instead: "!r #funny -#loud" picks quips tagged
+/// "funny" but not "loud". A quip must carry every included tag. "--long"
+/// asks for quips over the playback limit to be included.
+#[derive(Debug, Default, PartialEq)]
+pub struct Filter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub include_tags: Vec<String>,
+    pub exclude_tags: Vec<String>,
+    pub long: bool,
+    /// Quips longer than this many seconds are left out. Not parsed; it's up
+    /// to the caller whether the limit applies.
+    pub max_seconds: Option<f64>,
+    /// Quips that failed to play this many times in a row are left out. Not
+    /// parsed either.
+    pub broken_after: Option<u32>,
+}
+
+impl Filter {
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Filter {
+        let mut filter = Filter::default();
+        for arg in args {
+            let arg = arg.as_ref();
+            if arg == LONG_FLAG {
+                filter.long = true;
+                continue;
+            }
+            let (negated, arg) = match arg.strip_prefix('-') {
+                Some(arg) => (true, arg),
+                None => (false, arg),
+            };
+            match (negated, arg.strip_prefix('#')) {
+                (false, None) => filter.include.push(arg.to_string()),
+                (true, None) => filter.exclude.push(arg.to_string()),
+                (false, Some(tag)) => filter.include_tags.push(tag.to_lowercase()),
+                (true, Some(tag)) => filter.exclude_tags.push(tag.to_lowercase()),
+            }
+        }
+        filter
+    }
+
+    /// Whether the filter says anything about tags at all.
+    pub fn has_tags(&self) -> bool {
+        !self.include_tags.is_empty() || !self.exclude_tags.is_empty()
+    }
+
+    /// Whether quips from the given category may be chosen.
+    pub fn allows(&self, category: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|c| c == category))
+            && !self.exclude.iter().any(|c| c == category)
+    }
+
+    /// Whether a quip with the given tags may be chosen.
+    pub fn allows_tags(&self, tags: Option<&BTreeSet<String>>) -> bool {
+        let has = |tag: &String| tags.is_some_and(|tags| tags.contains(tag));
+        self.include_tags.iter().all(has) && !self.exclude_tags.iter().any(has)
+    }
+}
+
+/// Per-guild shuffle bags: every quip in the pool is played once before any
+/// is repeated. Rather than keeping a bag per filter, each guild has a single
+/// set of quips played so far, and a pool is refilled once all of its quips
+/// are in that set. That way "!r a1" and "!r" share one notion of "recently
+/// played".
+#[derive(Debug, Default)]
+pub struct ShuffleBags {
+    played: Mutex<HashMap<GuildId, HashSet<QuipId>>>,
+}
+
+impl ShuffleBags {
+    /// Choose an index into the pool, without repeats if `shuffle` is set.
+    /// If `counts` is given, quips with fewer plays are more likely to be
+    /// chosen. Returns None only for an empty pool.
+    pub fn choose<R: Rng>(
+        &self,
+        rng: &mut R,
+        guild_id: GuildId,
+        pool: &[QuipId],
+        shuffle: bool,
+        counts: Option<&HashMap<QuipId, u64>>,
+    ) -> Option<usize> {
+        if !shuffle {
+            let candidates: Vec<usize> = (0..pool.len()).collect();
+            return choose_weighted(rng, pool, &candidates, counts);
+        }
+
+        let mut guilds = self.played.lock().unwrap_or_else(PoisonError::into_inner);
+        let played = guilds.entry(guild_id).or_default();
+        let mut candidates: Vec<usize> = (0..pool.len())
+            .filter(|idx| !played.contains(&pool[*idx]))
+            .collect();
+        if candidates.is_empty() {
+            // Everything has been played: start the bag over.
+            for quip in pool {
+                played.remove(quip);
+            }
+            candidates = (0..pool.len()).collect();
+        }
+        let chosen = choose_weighted(rng, pool, &candidates, counts)?;
+        played.insert(pool[chosen].clone());
+        Some(chosen)
+    }
+
+    /// Carry a moved or renamed quip's played state over to its new name, so
+    /// the move doesn't put it back in every guild's bag.
+    pub fn rename(&self, from: &QuipId, to: &QuipId) {
+        let mut guilds = self.played.lock().unwrap_or_else(PoisonError::into_inner);
+        for played in guilds.values_mut() {
+            if played.remove(from) {
+                played.insert(to.clone());
+            }
+        }
+    }
+}
+
+/// Choose one of the candidate indices into the pool, uniformly or weighted by
+/// 1 / (1 + play count).
+fn choose_weighted<R: Rng>(
+    rng: &mut R,
+    pool: &[QuipId],
+    candidates: &[usize],
+    counts: Option<&HashMap<QuipId, u64>>,
+) -> Option<usize> {
+    if candidates.is_empty() {
+        return None;
+    }
+    let Some(counts) = counts else {
+        return Some(candidates[rng.random_range(0..candidates.len())]);
+    };
+    let weights = candidates
+        .iter()
+        .map(|idx| 1.0 / (1.0 + *counts.get(&pool[*idx]).unwrap_or(&0) as f64));
+    // Weights are always positive and finite, so this can't fail.
+    let dist = WeightedIndex::new(weights).ok()?;
+    Some(candidates[dist.sample(rng)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(n: usize) -> Vec<QuipId> {
+        (0..n)
+            .map(|i| ("a1".to_string(), format!("{}.mp3", i)))
+            .collect()
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse(&["a1", "a2", "-sw"]);
+        assert_eq!(
+            filter,
+            Filter {
+                include: vec!["a1".to_string(), "a2".to_string()],
+                exclude: vec!["sw".to_string()],
+                ..Default::default()
+            }
+        );
+        assert!(filter.allows("a1"));
+        assert!(!filter.allows("a3"));
+        assert!(!filter.allows("sw"));
+
+        let filter = Filter::parse(&["-sw", "--long"]);
+        assert!(filter.allows("a3"));
+        assert!(!filter.allows("sw"));
+        assert!(filter.long);
+        assert_eq!(filter.exclude, vec!["sw".to_string()]);
+
+        let filter = Filter::parse::<&str>(&[]);
+        assert!(filter.allows("anything"));
+        assert!(!filter.has_tags());
+        assert!(filter.allows_tags(None));
+    }
+
+    #[test]
+    fn test_filter_tags() {
+        let filter = Filter::parse(&["#Funny", "#short", "-#loud", "a1"]);
+        assert_eq!(filter.include, vec!["a1".to_string()]);
+        assert_eq!(
+            filter.include_tags,
+            vec!["funny".to_string(), "short".to_string()]
+        );
+        assert_eq!(filter.exclude_tags, vec!["loud".to_string()]);
+        assert!(filter.has_tags());
+
+        let tags =
+            |tags: &[&str]| -> BTreeSet<String> { tags.iter().map(|t| t.to_string()).collect() };
+        assert!(filter.allows_tags(Some(&tags(&["funny", "short"]))));
+        assert!(filter.allows_tags(Some(&tags(&["funny", "short", "victory"]))));
+        assert!(!filter.allows_tags(Some(&tags(&["funny"]))));
+        assert!(!filter.allows_tags(Some(&tags(&["funny", "short", "loud"]))));
+        assert!(!filter.allows_tags(None));
+
+        let filter = Filter::parse(&["-#loud"]);
+        assert!(filter.allows_tags(None));
+        assert!(!filter.allows_tags(Some(&tags(&["loud"]))));
+    }
+
+    #[test]
+    fn test_shuffle_plays_everything_once() {
+        let bags = ShuffleBags::default();
+        let pool = pool(20);
+        let guild_id = GuildId::new(1);
+        let mut rng = rand::rng();
+
+        for _ in 0..3 {
+            let mut seen = HashSet::new();
+            for _ in 0..pool.len() {
+                let idx = bags.choose(&mut rng, guild_id, &pool, true, None).unwrap();
+                assert!(seen.insert(idx));
+            }
+            assert_eq!(seen.len(), pool.len());
+        }
+    }
+
+    #[test]
+    fn test_shuffle_subset_pool() {
+        let bags = ShuffleBags::default();
+        let pool = pool(4);
+        let guild_id = GuildId::new(1);
+        let mut rng = rand::rng();
+
+        // Play two quips through a smaller pool first.
+        let small = &pool[..2];
+        for _ in 0..2 {
+            bags.choose(&mut rng, guild_id, small, true, None).unwrap();
+        }
+        // The full pool must now give the other two first.
+        let mut first: Vec<usize> = (0..2)
+            .map(|_| bags.choose(&mut rng, guild_id, &pool, true, None).unwrap())
+            .collect();
+        first.sort();
+        assert_eq!(first, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_shuffle_rename() {
+        let bags = ShuffleBags::default();
+        let pool = pool(2);
+        let guild_id = GuildId::new(1);
+        let mut rng = rand::rng();
+
+        let first = bags.choose(&mut rng, guild_id, &pool, true, None).unwrap();
+        let moved = ("a2".to_string(), "moved.mp3".to_string());
+        bags.rename(&pool[first], &moved);
+        // The moved quip still counts as played under its new name.
+        let renamed = vec![moved, pool[1 - first].clone()];
+        assert_eq!(
+            bags.choose(&mut rng, guild_id, &renamed, true, None),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_weighted() {
+        let bags = ShuffleBags::default();
+        let pool = pool(2);
+        let counts = HashMap::from([(pool[0].clone(), 99)]);
+        let guild_id = GuildId::new(1);
+        let mut rng = rand::rng();
+
+        let mut hits = [0; 2];
+        for _ in 0..1000 {
+            let idx = bags
+                .choose(&mut rng, guild_id, &pool, false, Some(&counts))
+                .unwrap();
+            hits[idx] += 1;
+        }
+        // Quip 1 is 100 times more likely; quip 0 should hardly ever come up.
+        assert!(hits[1] > 900);
+    }
+
+    #[test]
+    fn test_empty_pool() {
+        let bags = ShuffleBags::default();
+        let mut rng = rand::rng();
+        assert_eq!(
+            bags.choose(&mut rng, GuildId::new(1), &[], true, None),
+            None
+        );
+        assert_eq!(
+            bags.choose(&mut rng, GuildId::new(1), &[], false, None),
+            None
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use poise::serenity_prelude::{GuildId, UserId};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many entries each "top" list in "!stats" shows.
+pub const TOP_N: usize = 5;
+
+/// Longest name shown in a ranked list. File names can be very long.
+const MAX_NAME_CHARS: usize = 100;
+
+/// A single quip being played, as recorded for statistics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Play {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    pub category: String,
+    pub file_name: String,
+    /// Seconds since the Unix epoch.
+    pub played_at: i64,
+}
+
+/// Time window for statistics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Day,
+    Week,
+    Month,
+    All,
+}
+
+impl Window {
+    /// Unix timestamp at which the window starts, or None for all time.
+    /// Windows are rolling: "today" means the past 24 hours, since there's no
+    /// telling which time zone "today" is meant in.
+    pub fn since(&self, now: i64) -> Option<i64> {
+        const DAY: i64 = 24 * 60 * 60;
+        match self {
+            Window::Day => Some(now - DAY),
+            Window::Week => Some(now - 7 * DAY),
+            Window::Month => Some(now - 30 * DAY),
+            Window::All => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Window::Day => "the past day",
+            Window::Week => "the past week",
+            Window::Month => "the past 30 days",
+            Window::All => "all time",
+        }
+    }
+}
+
+/// Error for a time window that isn't one of the known ones.
+#[derive(Debug, PartialEq)]
+pub struct UnknownWindow(String);
+
+impl std::fmt::Display for UnknownWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Unknown time window {:?}. Use \"today\", \"week\", \"month\" or \"all\", or mention someone.",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownWindow {}
+
+impl FromStr for Window {
+    type Err = UnknownWindow;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "day" | "today" => Ok(Window::Day),
+            "week" => Ok(Window::Week),
+            "month" => Ok(Window::Month),
+            "all" | "alltime" | "all-time" => Ok(Window::All),
+            _ => Err(UnknownWindow(s.to_string())),
+        }
+    }
+}
+
+/// Current time as seconds since the Unix epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Format a ranked list, one entry per line, e.g. "1. a3: I Need Food (12)",
+/// leaving out entries that don't fit in `max_chars`. Returns a placeholder
+/// if the list is empty since Discord rejects empty embed fields.
+pub fn ranked<T: AsRef<str>>(entries: &[(T, u64)], max_chars: usize) -> String {
+    if entries.is_empty() {
+        return String::from("Nothing played yet.");
+    }
+    let mut out = String::new();
+    let mut chars = 0;
+    for (idx, (name, count)) in entries.iter().enumerate() {
+        let mut short: String = name.as_ref().chars().take(MAX_NAME_CHARS).collect();
+        if short.len() < name.as_ref().len() {
+            short.push('…');
+        }
+        let line = format!("{}. {} ({})", idx + 1, short, count);
+        let len = line.chars().count() + usize::from(idx > 0);
+        if chars + len > max_chars {
+            break;
+        }
+        if idx > 0 {
+            out.push('\n');
+        }
+        out.push_str(&line);
+        chars += len;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_from_str() {
+        assert_eq!("today".parse::<Window>(), Ok(Window::Day));
+        assert_eq!("Week".parse::<Window>(), Ok(Window::Week));
+        assert_eq!("month".parse::<Window>(), Ok(Window::Month));
+        assert_eq!("all-time".parse::<Window>(), Ok(Window::All));
+        assert!("fortnight".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn test_window_since() {
+        let now = 1_000_000_000;
+        assert_eq!(Window::Day.since(now), Some(now - 86_400));
+        assert_eq!(Window::Week.since(now), Some(now - 604_800));
+        assert_eq!(Window::All.since(now), None);
+    }
+
+    #[test]
+    fn test_ranked() {
+        assert_eq!(ranked::<&str>(&[], 1024), "Nothing played yet.");
+        assert_eq!(
+            ranked(&[("a3: Yes", 3), ("sw: No", 1)], 1024),
+            "1. a3: Yes (3)\n2. sw: No (1)"
+        );
+        // Entries that don't fit are left out, long names shortened.
+        assert_eq!(
+            ranked(&[("a3: Yes", 3), ("sw: No", 1)], 20),
+            "1. a3: Yes (3)"
+        );
+        let long = "x".repeat(500);
+        assert_eq!(ranked(&[(long, 1)], 1024).chars().count(), 108);
+    }
+}
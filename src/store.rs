@@ -0,0 +1,780 @@
+use crate::QuipId;
+use crate::review::{Status, Submission};
+use crate::stats::Play;
+use crate::tags::TagMap;
+use poise::serenity_prelude::{ChannelId, GuildId, UserId};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+/// Persistent storage for anything that needs to survive a restart: guild
+/// settings, user data, and the like. Commands get at it through
+/// `Data::store`, so the backing database can be swapped out (e.g. for tests).
+pub trait Store: Send + Sync {
+    /// The prefixes guilds have set with "!prefix".
+    fn prefixes(&self) -> Result<HashMap<GuildId, String>, crate::Error>;
+    /// Set, or with None clear, the prefix for the given guild.
+    fn set_prefix(&self, guild_id: GuildId, prefix: Option<&str>) -> Result<(), crate::Error>;
+
+    /// Record a quip being played.
+    fn record_play(&self, play: &Play) -> Result<(), crate::Error>;
+    /// Most played quips in a guild as ((category, file name), plays), most
+    /// played first. Optionally only counts plays by a single user and/or plays
+    /// since the given Unix timestamp.
+    fn top_quips(
+        &self,
+        guild_id: GuildId,
+        user_id: Option<UserId>,
+        since: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(QuipId, u64)>, crate::Error>;
+    /// Users who played the most quips in a guild, most active first.
+    fn top_users(
+        &self,
+        guild_id: GuildId,
+        since: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(UserId, u64)>, crate::Error>;
+    /// Number of plays of every quip ever played in a guild.
+    fn play_counts(&self, guild_id: GuildId) -> Result<HashMap<QuipId, u64>, crate::Error>;
+    /// Number of plays per category in a guild, busiest first.
+    fn category_totals(
+        &self,
+        guild_id: GuildId,
+        since: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, crate::Error>;
+
+    /// A user's favourite quips, in the order they were added.
+    fn favourites(&self, user_id: UserId) -> Result<Vec<QuipId>, crate::Error>;
+    /// Add a quip to a user's favourites. Returns false if it already was one.
+    fn add_favourite(&self, user_id: UserId, quip: &QuipId) -> Result<bool, crate::Error>;
+    /// Remove a quip from a user's favourites. Returns false if it wasn't one.
+    fn remove_favourite(&self, user_id: UserId, quip: &QuipId) -> Result<bool, crate::Error>;
+
+    /// Tags added with "!tag", shared by all guilds like the quips themselves.
+    fn tags(&self) -> Result<TagMap, crate::Error>;
+    /// Tag a quip. Returns false if it already had the tag.
+    fn add_tag(&self, quip: &QuipId, tag: &str) -> Result<bool, crate::Error>;
+    /// Untag a quip. Returns false if it didn't have the tag.
+    fn remove_tag(&self, quip: &QuipId, tag: &str) -> Result<bool, crate::Error>;
+
+    /// Point everything recorded about a quip (plays, favourites and tags) at
+    /// its new identity after it has been renamed or moved.
+    fn rename_quip(&self, from: &QuipId, to: &QuipId) -> Result<(), crate::Error>;
+
+    /// Record that a quip failed to play, returning how many times in a row
+    /// it has failed now.
+    fn record_failure(
+        &self,
+        quip: &QuipId,
+        reason: &str,
+        failed_at: i64,
+    ) -> Result<u64, crate::Error>;
+    /// Forget a quip's failures, since it played fine.
+    fn clear_failures(&self, quip: &QuipId) -> Result<(), crate::Error>;
+    /// How many times in a row each quip that last failed has failed.
+    fn failure_counts(&self) -> Result<HashMap<QuipId, u64>, crate::Error>;
+
+    /// Record a quip submitted for review, returning its ID. The submission's
+    /// own ID is ignored.
+    fn add_submission(&self, submission: &Submission) -> Result<i64, crate::Error>;
+    fn submission(&self, id: i64) -> Result<Option<Submission>, crate::Error>;
+    /// Approve or reject a pending submission. Returns false if it wasn't
+    /// pending anymore, e.g. because another moderator was quicker.
+    fn decide_submission(
+        &self,
+        id: i64,
+        decision: Status,
+        decided_by: UserId,
+        decided_at: i64,
+    ) -> Result<bool, crate::Error>;
+}
+
+/// Schema migrations, applied in order. The database's `user_version` records
+/// how many have been applied, so only ever append to this list; never edit
+/// or reorder existing entries.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE guild_settings (
+        guild_id INTEGER PRIMARY KEY,
+        prefix TEXT
+    );",
+    "CREATE TABLE plays (
+        id INTEGER PRIMARY KEY,
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        category TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        played_at INTEGER NOT NULL
+    );
+    CREATE INDEX plays_guild_time ON plays (guild_id, played_at);",
+    "CREATE TABLE favourites (
+        id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        category TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        UNIQUE (user_id, category, file_name)
+    );",
+    "CREATE TABLE tags (
+        id INTEGER PRIMARY KEY,
+        category TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        UNIQUE (category, file_name, tag)
+    );",
+    "CREATE TABLE submissions (
+        id INTEGER PRIMARY KEY,
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        channel_id INTEGER NOT NULL,
+        category TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        pending_file TEXT NOT NULL,
+        seconds REAL NOT NULL,
+        status TEXT NOT NULL,
+        submitted_at INTEGER NOT NULL,
+        decided_by INTEGER,
+        decided_at INTEGER
+    );",
+    "CREATE TABLE failures (
+        category TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        failures INTEGER NOT NULL,
+        reason TEXT NOT NULL,
+        failed_at INTEGER NOT NULL,
+        PRIMARY KEY (category, file_name)
+    );",
+];
+
+/// SQLite implementation of the Store, kept in a single file.
+pub struct SqliteStore {
+    // rusqlite connections can't be shared between threads, but queries are
+    // quick enough that a plain mutex is fine for a bot of this size. A
+    // panic mid-query leaves nothing half-written (transactions roll back),
+    // so a poisoned lock is still good to use.
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (creating if needed) the database at the given path and bring its
+    /// schema up to date.
+    pub fn open(path: &Path) -> Result<SqliteStore, crate::Error> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// A throwaway database that lives only as long as the store.
+    #[cfg(test)]
+    pub fn in_memory() -> Result<SqliteStore, crate::Error> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<SqliteStore, crate::Error> {
+        migrate(&mut conn)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+/// SQLite only has signed integers. Discord IDs are snowflakes whose top bit is
+/// always clear, so they fit in an i64 without loss.
+fn sql_id(id: u64) -> i64 {
+    id as i64
+}
+
+/// Apply any migrations the database hasn't seen yet, each in its own
+/// transaction so a failure leaves the database at the last good version.
+fn migrate(conn: &mut Connection) -> Result<(), crate::Error> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let version = version as usize;
+    if version > MIGRATIONS.len() {
+        return Err(crate::Error::Internal(
+            format!(
+                "Database schema version {} is newer than this version of the bot supports ({}).",
+                version,
+                MIGRATIONS.len()
+            )
+            .into(),
+        ));
+    }
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        // PRAGMA doesn't support bound parameters.
+        tx.execute_batch(&format!("PRAGMA user_version = {}", idx + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+impl Store for SqliteStore {
+    fn prefixes(&self) -> Result<HashMap<GuildId, String>, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt =
+            conn.prepare("SELECT guild_id, prefix FROM guild_settings WHERE prefix IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((GuildId::new(row.get::<_, i64>(0)? as u64), row.get(1)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn set_prefix(&self, guild_id: GuildId, prefix: Option<&str>) -> Result<(), crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        conn.execute(
+            "INSERT INTO guild_settings (guild_id, prefix) VALUES (?1, ?2)
+             ON CONFLICT (guild_id) DO UPDATE SET prefix = excluded.prefix",
+            params![sql_id(guild_id.get()), prefix],
+        )?;
+        Ok(())
+    }
+
+    fn record_play(&self, play: &Play) -> Result<(), crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        conn.execute(
+            "INSERT INTO plays (guild_id, user_id, category, file_name, played_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                sql_id(play.guild_id.get()),
+                sql_id(play.user_id.get()),
+                play.category,
+                play.file_name,
+                play.played_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn top_quips(
+        &self,
+        guild_id: GuildId,
+        user_id: Option<UserId>,
+        since: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(QuipId, u64)>, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt = conn.prepare(
+            "SELECT category, file_name, COUNT(*) AS n FROM plays
+             WHERE guild_id = ?1 AND (?2 IS NULL OR user_id = ?2) AND (?3 IS NULL OR played_at >= ?3)
+             GROUP BY category, file_name ORDER BY n DESC, category, file_name LIMIT ?4",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                sql_id(guild_id.get()),
+                user_id.map(|u| sql_id(u.get())),
+                since,
+                limit as i64
+            ],
+            |row| Ok(((row.get(0)?, row.get(1)?), row.get::<_, i64>(2)? as u64)),
+        )?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn top_users(
+        &self,
+        guild_id: GuildId,
+        since: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(UserId, u64)>, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt = conn.prepare(
+            "SELECT user_id, COUNT(*) AS n FROM plays
+             WHERE guild_id = ?1 AND (?2 IS NULL OR played_at >= ?2)
+             GROUP BY user_id ORDER BY n DESC, user_id LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(
+            params![sql_id(guild_id.get()), since, limit as i64],
+            |row| {
+                Ok((
+                    UserId::new(row.get::<_, i64>(0)? as u64),
+                    row.get::<_, i64>(1)? as u64,
+                ))
+            },
+        )?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn play_counts(&self, guild_id: GuildId) -> Result<HashMap<QuipId, u64>, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt = conn.prepare(
+            "SELECT category, file_name, COUNT(*) FROM plays WHERE guild_id = ?1
+             GROUP BY category, file_name",
+        )?;
+        let rows = stmt.query_map(params![sql_id(guild_id.get())], |row| {
+            Ok(((row.get(0)?, row.get(1)?), row.get::<_, i64>(2)? as u64))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn category_totals(
+        &self,
+        guild_id: GuildId,
+        since: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(String, u64)>, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt = conn.prepare(
+            "SELECT category, COUNT(*) AS n FROM plays
+             WHERE guild_id = ?1 AND (?2 IS NULL OR played_at >= ?2)
+             GROUP BY category ORDER BY n DESC, category LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(
+            params![sql_id(guild_id.get()), since, limit as i64],
+            |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)),
+        )?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn favourites(&self, user_id: UserId) -> Result<Vec<QuipId>, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt = conn
+            .prepare("SELECT category, file_name FROM favourites WHERE user_id = ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![sql_id(user_id.get())], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn add_favourite(&self, user_id: UserId, quip: &QuipId) -> Result<bool, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let added = conn.execute(
+            "INSERT OR IGNORE INTO favourites (user_id, category, file_name) VALUES (?1, ?2, ?3)",
+            params![sql_id(user_id.get()), quip.0, quip.1],
+        )?;
+        Ok(added > 0)
+    }
+
+    fn remove_favourite(&self, user_id: UserId, quip: &QuipId) -> Result<bool, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let removed = conn.execute(
+            "DELETE FROM favourites WHERE user_id = ?1 AND category = ?2 AND file_name = ?3",
+            params![sql_id(user_id.get()), quip.0, quip.1],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn tags(&self) -> Result<TagMap, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt = conn.prepare("SELECT category, file_name, tag FROM tags")?;
+        let mut rows = stmt.query([])?;
+        let mut tags = TagMap::new();
+        while let Some(row) = rows.next()? {
+            tags.entry((row.get(0)?, row.get(1)?))
+                .or_default()
+                .insert(row.get(2)?);
+        }
+        Ok(tags)
+    }
+
+    fn add_tag(&self, quip: &QuipId, tag: &str) -> Result<bool, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let added = conn.execute(
+            "INSERT OR IGNORE INTO tags (category, file_name, tag) VALUES (?1, ?2, ?3)",
+            params![quip.0, quip.1, tag],
+        )?;
+        Ok(added > 0)
+    }
+
+    fn remove_tag(&self, quip: &QuipId, tag: &str) -> Result<bool, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let removed = conn.execute(
+            "DELETE FROM tags WHERE category = ?1 AND file_name = ?2 AND tag = ?3",
+            params![quip.0, quip.1, tag],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn rename_quip(&self, from: &QuipId, to: &QuipId) -> Result<(), crate::Error> {
+        let mut conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let tx = conn.transaction()?;
+        for table in ["plays", "favourites", "tags", "failures"] {
+            // Rows that would clash with existing ones for the new name (e.g.
+            // favourites of a since deleted quip of that name) are dropped.
+            tx.execute(
+                &format!(
+                    "UPDATE OR IGNORE {} SET category = ?3, file_name = ?4
+                     WHERE category = ?1 AND file_name = ?2",
+                    table
+                ),
+                params![from.0, from.1, to.0, to.1],
+            )?;
+            tx.execute(
+                &format!(
+                    "DELETE FROM {} WHERE category = ?1 AND file_name = ?2",
+                    table
+                ),
+                params![from.0, from.1],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn record_failure(
+        &self,
+        quip: &QuipId,
+        reason: &str,
+        failed_at: i64,
+    ) -> Result<u64, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let failures: i64 = conn.query_row(
+            "INSERT INTO failures (category, file_name, failures, reason, failed_at)
+             VALUES (?1, ?2, 1, ?3, ?4)
+             ON CONFLICT (category, file_name) DO UPDATE SET
+                 failures = failures + 1,
+                 reason = excluded.reason,
+                 failed_at = excluded.failed_at
+             RETURNING failures",
+            params![quip.0, quip.1, reason, failed_at],
+            |row| row.get(0),
+        )?;
+        Ok(failures as u64)
+    }
+
+    fn clear_failures(&self, quip: &QuipId) -> Result<(), crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        conn.execute(
+            "DELETE FROM failures WHERE category = ?1 AND file_name = ?2",
+            params![quip.0, quip.1],
+        )?;
+        Ok(())
+    }
+
+    fn failure_counts(&self) -> Result<HashMap<QuipId, u64>, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut stmt = conn.prepare("SELECT category, file_name, failures FROM failures")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(((row.get(0)?, row.get(1)?), row.get::<_, i64>(2)? as u64))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn add_submission(&self, submission: &Submission) -> Result<i64, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        conn.execute(
+            "INSERT INTO submissions (guild_id, user_id, channel_id, category, file_name,
+                pending_file, seconds, status, submitted_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                sql_id(submission.guild_id.get()),
+                sql_id(submission.user_id.get()),
+                sql_id(submission.channel_id.get()),
+                submission.category,
+                submission.file_name,
+                submission.pending_file,
+                submission.seconds,
+                submission.status.as_str(),
+                submission.submitted_at
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn submission(&self, id: i64) -> Result<Option<Submission>, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let submission = conn
+            .query_row(
+                "SELECT guild_id, user_id, channel_id, category, file_name, pending_file,
+                    seconds, status, submitted_at
+                 FROM submissions WHERE id = ?1",
+                params![id],
+                |row| {
+                    let status: String = row.get(7)?;
+                    Ok(Submission {
+                        id,
+                        guild_id: GuildId::new(row.get::<_, i64>(0)? as u64),
+                        user_id: UserId::new(row.get::<_, i64>(1)? as u64),
+                        channel_id: ChannelId::new(row.get::<_, i64>(2)? as u64),
+                        category: row.get(3)?,
+                        file_name: row.get(4)?,
+                        pending_file: row.get(5)?,
+                        seconds: row.get(6)?,
+                        status: Status::parse(&status).ok_or_else(|| {
+                            rusqlite::Error::FromSqlConversionFailure(
+                                7,
+                                rusqlite::types::Type::Text,
+                                format!("unknown submission status \"{}\"", status).into(),
+                            )
+                        })?,
+                        submitted_at: row.get(8)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(submission)
+    }
+
+    fn decide_submission(
+        &self,
+        id: i64,
+        decision: Status,
+        decided_by: UserId,
+        decided_at: i64,
+    ) -> Result<bool, crate::Error> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let updated = conn.execute(
+            "UPDATE submissions SET status = ?2, decided_by = ?3, decided_at = ?4
+             WHERE id = ?1 AND status = ?5",
+            params![
+                id,
+                decision.as_str(),
+                sql_id(decided_by.get()),
+                decided_at,
+                Status::Pending.as_str()
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+
+        // Running again is a no-op.
+        migrate(&mut conn).unwrap();
+
+        // A database from the future is refused.
+        conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1))
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+
+    #[test]
+    fn test_prefix() {
+        let store = SqliteStore::in_memory().unwrap();
+        let guild_id = GuildId::new(1);
+        assert!(store.prefixes().unwrap().is_empty());
+
+        store.set_prefix(guild_id, Some("?")).unwrap();
+        store.set_prefix(GuildId::new(2), Some("$")).unwrap();
+        let prefixes = store.prefixes().unwrap();
+        assert_eq!(prefixes.len(), 2);
+        assert_eq!(prefixes[&guild_id], "?");
+
+        store.set_prefix(guild_id, None).unwrap();
+        let prefixes = store.prefixes().unwrap();
+        assert_eq!(prefixes.get(&guild_id), None);
+        assert_eq!(prefixes[&GuildId::new(2)], "$");
+    }
+
+    fn play(guild: u64, user: u64, category: &str, file_name: &str, played_at: i64) -> Play {
+        Play {
+            guild_id: GuildId::new(guild),
+            user_id: UserId::new(user),
+            category: category.to_string(),
+            file_name: file_name.to_string(),
+            played_at,
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let store = SqliteStore::in_memory().unwrap();
+        let plays = [
+            play(1, 10, "a3", "Yes.mp3", 100),
+            play(1, 10, "a3", "Yes.mp3", 200),
+            play(1, 11, "a3", "Yes.mp3", 300),
+            play(1, 11, "sw", "No.mp3", 300),
+            play(1, 11, "sw", "Hmm.mp3", 400),
+            play(1, 11, "sw", "Hmm.mp3", 400),
+            play(1, 11, "sw", "Hmm.mp3", 400),
+            // Another guild, which should never show up.
+            play(2, 10, "a3", "No.mp3", 100),
+        ];
+        for p in plays.iter() {
+            store.record_play(p).unwrap();
+        }
+        let guild_id = GuildId::new(1);
+        let quip = |c: &str, f: &str| (c.to_string(), f.to_string());
+
+        assert_eq!(
+            store.top_quips(guild_id, None, None, 2).unwrap(),
+            vec![(quip("a3", "Yes.mp3"), 3), (quip("sw", "Hmm.mp3"), 3)]
+        );
+        assert_eq!(
+            store
+                .top_quips(guild_id, Some(UserId::new(10)), None, 5)
+                .unwrap(),
+            vec![(quip("a3", "Yes.mp3"), 2)]
+        );
+        assert_eq!(
+            store.top_quips(guild_id, None, Some(300), 5).unwrap(),
+            vec![
+                (quip("sw", "Hmm.mp3"), 3),
+                (quip("a3", "Yes.mp3"), 1),
+                (quip("sw", "No.mp3"), 1)
+            ]
+        );
+        assert_eq!(
+            store.top_users(guild_id, None, 5).unwrap(),
+            vec![(UserId::new(11), 5), (UserId::new(10), 2)]
+        );
+        assert_eq!(
+            store.top_users(guild_id, Some(250), 5).unwrap(),
+            vec![(UserId::new(11), 5)]
+        );
+        let counts = store.play_counts(guild_id).unwrap();
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[&quip("sw", "Hmm.mp3")], 3);
+        assert_eq!(counts[&quip("sw", "No.mp3")], 1);
+        assert_eq!(
+            store.category_totals(guild_id, None, 5).unwrap(),
+            vec![("sw".to_string(), 4), ("a3".to_string(), 3)]
+        );
+        assert_eq!(
+            store.category_totals(guild_id, None, 1).unwrap(),
+            vec![("sw".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn test_favourites() {
+        let store = SqliteStore::in_memory().unwrap();
+        let user_id = UserId::new(10);
+        let yes = ("a3".to_string(), "Yes.mp3".to_string());
+        let no = ("a3".to_string(), "No.mp3".to_string());
+        let hmm = ("sw".to_string(), "Hmm.mp3".to_string());
+
+        assert!(store.favourites(user_id).unwrap().is_empty());
+        assert!(store.add_favourite(user_id, &yes).unwrap());
+        assert!(store.add_favourite(user_id, &no).unwrap());
+        assert!(store.add_favourite(user_id, &hmm).unwrap());
+        assert!(!store.add_favourite(user_id, &no).unwrap());
+        assert!(store.add_favourite(UserId::new(11), &hmm).unwrap());
+        assert_eq!(
+            store.favourites(user_id).unwrap(),
+            vec![yes.clone(), no.clone(), hmm.clone()]
+        );
+
+        assert!(store.remove_favourite(user_id, &no).unwrap());
+        assert!(!store.remove_favourite(user_id, &no).unwrap());
+        assert_eq!(store.favourites(user_id).unwrap(), vec![yes, hmm.clone()]);
+        assert_eq!(store.favourites(UserId::new(11)).unwrap(), vec![hmm]);
+    }
+
+    #[test]
+    fn test_tags() {
+        let store = SqliteStore::in_memory().unwrap();
+        let yes = ("a3".to_string(), "Yes.mp3".to_string());
+        let hmm = ("sw".to_string(), "Hmm.mp3".to_string());
+
+        assert!(store.tags().unwrap().is_empty());
+        assert!(store.add_tag(&yes, "funny").unwrap());
+        assert!(store.add_tag(&yes, "short").unwrap());
+        assert!(!store.add_tag(&yes, "funny").unwrap());
+        assert!(store.add_tag(&hmm, "funny").unwrap());
+
+        let tags = store.tags().unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(
+            tags[&yes].iter().collect::<Vec<_>>(),
+            vec!["funny", "short"]
+        );
+
+        assert!(store.remove_tag(&yes, "funny").unwrap());
+        assert!(!store.remove_tag(&yes, "funny").unwrap());
+        assert_eq!(store.tags().unwrap()[&yes].len(), 1);
+    }
+
+    #[test]
+    fn test_submissions() {
+        let store = SqliteStore::in_memory().unwrap();
+        let mut submission = Submission {
+            id: 0,
+            guild_id: GuildId::new(1),
+            user_id: UserId::new(10),
+            channel_id: ChannelId::new(100),
+            category: "a3".to_string(),
+            file_name: "Yes.mp3".to_string(),
+            pending_file: "10-1000-Yes.mp3".to_string(),
+            seconds: 1.5,
+            status: Status::Pending,
+            submitted_at: 1000,
+        };
+        let id = store.add_submission(&submission).unwrap();
+        submission.id = id;
+        assert_eq!(store.submission(id).unwrap(), Some(submission.clone()));
+        assert_eq!(store.submission(id + 1).unwrap(), None);
+
+        assert!(
+            store
+                .decide_submission(id, Status::Approved, UserId::new(11), 2000)
+                .unwrap()
+        );
+        // Only the first decision counts.
+        assert!(
+            !store
+                .decide_submission(id, Status::Rejected, UserId::new(12), 2001)
+                .unwrap()
+        );
+        assert_eq!(
+            store.submission(id).unwrap().unwrap().status,
+            Status::Approved
+        );
+    }
+
+    #[test]
+    fn test_rename_quip() {
+        let store = SqliteStore::in_memory().unwrap();
+        let guild_id = GuildId::new(1);
+        let user_id = UserId::new(10);
+        let old = ("a3".to_string(), "Yes.mp3".to_string());
+        let new = ("misc".to_string(), "Yeah.mp3".to_string());
+        store
+            .record_play(&play(1, 10, "a3", "Yes.mp3", 100))
+            .unwrap();
+        store.add_favourite(user_id, &old).unwrap();
+        store.add_tag(&old, "funny").unwrap();
+
+        store.rename_quip(&old, &new).unwrap();
+        assert_eq!(
+            store.top_quips(guild_id, None, None, 5).unwrap(),
+            vec![(new.clone(), 1)]
+        );
+        assert_eq!(store.favourites(user_id).unwrap(), vec![new.clone()]);
+        assert!(store.tags().unwrap()[&new].contains("funny"));
+        assert!(!store.tags().unwrap().contains_key(&old));
+
+        // A clashing favourite is dropped rather than duplicated.
+        store.add_favourite(user_id, &old).unwrap();
+        store.rename_quip(&old, &new).unwrap();
+        assert_eq!(store.favourites(user_id).unwrap(), vec![new]);
+    }
+
+    #[test]
+    fn test_failures() {
+        let store = SqliteStore::in_memory().unwrap();
+        let quip = ("a3".to_string(), "Yes.mp3".to_string());
+        let other = ("a3".to_string(), "No.mp3".to_string());
+        assert_eq!(store.record_failure(&quip, "damaged", 100).unwrap(), 1);
+        assert_eq!(store.record_failure(&quip, "damaged", 200).unwrap(), 2);
+        assert_eq!(store.record_failure(&other, "missing", 300).unwrap(), 1);
+        assert_eq!(
+            store.failure_counts().unwrap(),
+            HashMap::from([(quip.clone(), 2), (other.clone(), 1)])
+        );
+
+        // Playing fine starts the count over.
+        store.clear_failures(&quip).unwrap();
+        assert_eq!(
+            store.failure_counts().unwrap(),
+            HashMap::from([(other.clone(), 1)])
+        );
+        assert_eq!(store.record_failure(&quip, "damaged", 400).unwrap(), 1);
+
+        // Renamed quips keep their failures.
+        let renamed = ("misc".to_string(), "Nope.mp3".to_string());
+        store.rename_quip(&other, &renamed).unwrap();
+        assert_eq!(store.failure_counts().unwrap()[&renamed], 1);
+    }
+}
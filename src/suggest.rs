@@ -0,0 +1,113 @@
+/// Edit distance between two strings, counted in characters rather than
+/// bytes so non-ASCII names don't get penalized. Swapping two neighbouring
+/// characters, the most common typo, counts as a single edit (optimal string
+/// alignment distance).
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // Dynamic programming over three rows: transpositions look two back.
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr: Vec<usize> = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        curr[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == cb { 0 } else { 1 };
+            curr[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(curr[j] + 1);
+            if i > 0 && j > 0 && *ca == b[j - 1] && a[i - 1] == *cb {
+                curr[j + 1] = curr[j + 1].min(before[j - 1] + 1);
+            }
+        }
+        std::mem::swap(&mut before, &mut prev);
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+/// Largest edit distance that still counts as a plausible typo for the
+/// given input. Short inputs (like category names) allow a single edit.
+fn max_distance(input: &str) -> usize {
+    (input.chars().count() / 3).max(1)
+}
+
+/// Most suggestions to offer at once when several candidates are equally close.
+const MAX_SUGGESTIONS: usize = 5;
+
+/// Find the candidates closest to `input` (case-insensitive), provided they
+/// are close enough to plausibly be a typo. Equally close candidates are all
+/// returned, in the order they were given, so "a4" suggests "a1", "a2" and
+/// "a3" rather than arbitrarily picking one.
+pub fn closest<'a, I>(input: &str, candidates: I) -> Vec<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let input = input.to_lowercase();
+    let mut best: Vec<&str> = Vec::new();
+    let mut best_dist = max_distance(&input);
+    for candidate in candidates {
+        let dist = edit_distance(&input, &candidate.to_lowercase());
+        if dist < best_dist {
+            best.clear();
+            best_dist = dist;
+        }
+        if dist == best_dist && !best.contains(&candidate) {
+            best.push(candidate);
+        }
+    }
+    best.truncate(MAX_SUGGESTIONS);
+    best
+}
+
+/// Format the "did you mean" tail for an error message, or an empty string
+/// if there are no suggestions.
+pub fn did_you_mean(suggestions: &[&str]) -> String {
+    let quoted: Vec<String> = suggestions.iter().map(|s| format!("`{}`", s)).collect();
+    match quoted.as_slice() {
+        [] => String::new(),
+        [only] => format!("; did you mean {}?", only),
+        [rest @ .., last] => format!("; did you mean {} or {}?", rest.join(", "), last),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("a3", "a3"), 0);
+        assert_eq!(edit_distance("a4", "a3"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("lsit", "list"), 1);
+        assert_eq!(edit_distance("ab", "ba"), 1);
+        assert_eq!(edit_distance("ca", "abc"), 3);
+    }
+
+    #[test]
+    fn test_closest() {
+        let candidates = ["a1", "a2", "a3", "halo", "list", "random"];
+        assert_eq!(closest("a3", candidates), vec!["a3"]);
+        assert_eq!(closest("a4", candidates), vec!["a1", "a2", "a3"]);
+        assert_eq!(closest("hallo", candidates), vec!["halo"]);
+        assert_eq!(closest("RANDON", candidates), vec!["random"]);
+        assert_eq!(closest("lisst", candidates), vec!["list"]);
+        assert_eq!(closest("lsit", candidates), vec!["list"]);
+        assert_eq!(closest("rnadom", candidates), vec!["random"]);
+        // Nothing is remotely close.
+        assert!(closest("zzzzzz", candidates).is_empty());
+        assert!(closest("x", std::iter::empty()).is_empty());
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        assert_eq!(did_you_mean(&[]), "");
+        assert_eq!(did_you_mean(&["a3"]), "; did you mean `a3`?");
+        assert_eq!(
+            did_you_mean(&["a1", "a2", "a3"]),
+            "; did you mean `a1`, `a2` or `a3`?"
+        );
+    }
+}
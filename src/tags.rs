@@ -0,0 +1,162 @@
+use crate::QuipId;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// Name of the optional tags file at the top of the audio directory.
+pub const SIDECAR: &str = "tags.toml";
+
+/// Longest tag allowed, to keep "!tags" readable.
+pub const MAX_TAG_LEN: usize = 32;
+
+/// Tags of each quip that has any.
+pub type TagMap = HashMap<QuipId, BTreeSet<String>>;
+
+/// Layout of the sidecar file: category, then file name, then its tags, e.g.
+///
+/// ```toml
+/// [a1]
+/// "I Need Food.mp3" = ["funny", "hungry"]
+/// ```
+type Sidecar = HashMap<String, HashMap<String, Vec<String>>>;
+
+/// Normalize a tag as typed by a user, with or without its leading '#', to its
+/// canonical lowercase form. Returns a reason if it isn't a valid tag.
+pub fn normalize(tag: &str) -> Result<String, String> {
+    let tag = tag.strip_prefix('#').unwrap_or(tag).to_lowercase();
+    if tag.is_empty() {
+        return Err("Tags cannot be empty.".to_string());
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "Invalid tag \"{}\": use only letters, numbers, '-' and '_'.",
+            tag
+        ));
+    }
+    if tag.chars().count() > MAX_TAG_LEN {
+        return Err(format!(
+            "Tags can be at most {} characters long.",
+            MAX_TAG_LEN
+        ));
+    }
+    Ok(tag)
+}
+
+/// Load the sidecar tags file. A missing file just means no tags.
+pub fn load_sidecar(path: &Path) -> Result<TagMap, crate::Error> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(TagMap::new()),
+        Err(e) => {
+            return Err(crate::Error::Library(format!(
+                "Failed to read {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    parse_sidecar(&text)
+        .map_err(|e| crate::Error::Library(format!("Failed to parse {}: {}", path.display(), e)))
+}
+
+fn parse_sidecar(text: &str) -> Result<TagMap, String> {
+    let sidecar: Sidecar = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut tags = TagMap::new();
+    for (category, files) in sidecar {
+        for (file_name, file_tags) in files {
+            let file_tags = file_tags
+                .iter()
+                .map(|t| normalize(t))
+                .collect::<Result<BTreeSet<String>, String>>()?;
+            tags.entry((category.clone(), file_name))
+                .or_default()
+                .extend(file_tags);
+        }
+    }
+    Ok(tags)
+}
+
+/// Add every tag in `other` to `tags`.
+pub fn merge(tags: &mut TagMap, other: &TagMap) {
+    for (quip, quip_tags) in other {
+        tags.entry(quip.clone())
+            .or_default()
+            .extend(quip_tags.iter().cloned());
+    }
+}
+
+/// Number of quips carrying each tag, most used first and then by name.
+pub fn counts(tags: &TagMap) -> Vec<(String, u64)> {
+    let mut counts: HashMap<&str, u64> = HashMap::new();
+    for tag in tags.values().flatten() {
+        *counts.entry(tag).or_default() += 1;
+    }
+    let mut counts: Vec<(String, u64)> = counts
+        .into_iter()
+        .map(|(tag, count)| (tag.to_string(), count))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quip(category: &str, file_name: &str) -> QuipId {
+        (category.to_string(), file_name.to_string())
+    }
+
+    fn set(tags: &[&str]) -> BTreeSet<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("#Victory"), Ok("victory".to_string()));
+        assert_eq!(normalize("so_loud-1"), Ok("so_loud-1".to_string()));
+        assert!(normalize("#").is_err());
+        assert!(normalize("two words").is_err());
+        assert!(normalize(&"a".repeat(MAX_TAG_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_sidecar() {
+        let tags = parse_sidecar(
+            r##"
+[a1]
+"Hmm.mp3" = ["Funny", "#loud"]
+
+[sw]
+"No.mp3" = ["funny"]
+"##,
+        )
+        .unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[&quip("a1", "Hmm.mp3")], set(&["funny", "loud"]));
+        assert_eq!(tags[&quip("sw", "No.mp3")], set(&["funny"]));
+
+        assert!(parse_sidecar("[a1]\n\"Hmm.mp3\" = [\"not valid\"]").is_err());
+    }
+
+    #[test]
+    fn test_merge_and_counts() {
+        let mut tags = TagMap::from([(quip("a1", "Hmm.mp3"), set(&["funny"]))]);
+        let other = TagMap::from([
+            (quip("a1", "Hmm.mp3"), set(&["loud"])),
+            (quip("sw", "No.mp3"), set(&["funny", "victory"])),
+        ]);
+        merge(&mut tags, &other);
+        assert_eq!(tags[&quip("a1", "Hmm.mp3")], set(&["funny", "loud"]));
+        assert_eq!(
+            counts(&tags),
+            vec![
+                ("funny".to_string(), 2),
+                ("loud".to_string(), 1),
+                ("victory".to_string(), 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use crate::audio;
+use crate::config::UploadConfig;
+use poise::serenity_prelude::PremiumTier;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Longest quip name allowed, not counting the extension.
+pub const MAX_NAME_LEN: usize = 100;
+
+/// Work out the file name for an uploaded quip: the given name, or else the
+/// attachment's own name, with the attachment's extension. Returns a reason if
+/// the attachment isn't a supported format or the name isn't usable.
+pub fn file_name(name: Option<&str>, attachment_name: &str) -> Result<String, String> {
+    let attachment = Path::new(attachment_name);
+    let extension = audio::extension(attachment);
+    if !audio::is_supported(attachment) {
+        return Err(format!(
+            "\"{}\" is not a supported audio file. Supported formats: {}.",
+            attachment_name,
+            audio::EXTENSIONS.join(", ")
+        ));
+    }
+
+    let stem = match name {
+        Some(name) => name.trim().to_string(),
+        None => attachment
+            .file_stem()
+            .map(|s| s.to_string_lossy().trim().to_string())
+            .unwrap_or_default(),
+    };
+    check_name(&stem)?;
+    Ok(format!("{}.{}", stem, extension))
+}
+
+/// Check that a quip name (without extension) is usable as a file name,
+/// returning a reason if it isn't.
+pub fn check_name(stem: &str) -> Result<(), String> {
+    if stem.is_empty() {
+        return Err("The quip name cannot be empty.".to_string());
+    }
+    if stem.starts_with('.') || stem.chars().any(|c| c.is_control() || "/\\".contains(c)) {
+        return Err(format!(
+            "\"{}\" can't be used as a quip name: it can't start with '.' or contain slashes.",
+            stem
+        ));
+    }
+    if stem.chars().count() > MAX_NAME_LEN {
+        return Err(format!(
+            "Quip names can be at most {} characters long.",
+            MAX_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+/// Download a file, giving up as soon as it turns out to be larger than
+/// `max_bytes`. Failing to download is on Discord or the network, not the
+/// uploader, so it's an internal error.
+pub async fn download(
+    client: &reqwest::Client,
+    url: &str,
+    max_bytes: u64,
+) -> Result<Vec<u8>, crate::Error> {
+    let too_large = || {
+        crate::Error::Input(format!(
+            "The file is too large, the limit is {} KB.",
+            max_bytes / 1000
+        ))
+    };
+    let failed = |e: reqwest::Error| {
+        crate::Error::Internal(format!("Failed to download {}: {}", url, e).into())
+    };
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(failed)?;
+    if response.content_length().is_some_and(|len| len > max_bytes) {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(failed)? {
+        if (bytes.len() + chunk.len()) as u64 > max_bytes {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Check that the bytes are audio the bot can play, returning its duration in
+/// seconds.
+pub fn probe(bytes: Vec<u8>, extension: &str) -> Result<f64, String> {
+    Ok(audio::analyze(Box::new(Cursor::new(bytes)), extension)?.seconds)
+}
+
+/// Check a downloaded upload against the configured limits, returning its
+/// duration in seconds.
+pub fn validate(bytes: Vec<u8>, extension: &str, limits: &UploadConfig) -> Result<f64, String> {
+    if bytes.len() as u64 > limits.max_bytes {
+        return Err(format!(
+            "The file is too large, the limit is {} KB.",
+            limits.max_bytes / 1000
+        ));
+    }
+    let seconds = probe(bytes, extension)?;
+    if seconds > limits.max_seconds {
+        return Err(format!(
+            "The clip is {:.1} seconds long, the limit is {} seconds.",
+            seconds, limits.max_seconds
+        ));
+    }
+    Ok(seconds)
+}
+
+/// Largest attachment the bot can send in a guild with the given boost level,
+/// in bytes.
+pub fn discord_limit(tier: PremiumTier) -> u64 {
+    const MB: u64 = 1024 * 1024;
+    match tier {
+        PremiumTier::Tier2 => 50 * MB,
+        PremiumTier::Tier3 => 100 * MB,
+        _ => 10 * MB,
+    }
+}
+
+/// Save a quip into a directory of the audio directory, normally its
+/// category's. The file is written into the top-level audio directory first,
+/// which isn't scanned for quips, and then moved into place so a half-written
+/// file is never picked up.
+pub fn save(
+    top_dir: &Path,
+    dir: &str,
+    file_name: &str,
+    bytes: &[u8],
+) -> Result<PathBuf, crate::Error> {
+    let path = top_dir.join(dir).join(file_name);
+    if path.exists() {
+        return Err(format!("A quip named \"{}\" already exists.", file_name).into());
+    }
+    let staging = top_dir.join(format!(".upload-{}", file_name));
+    std::fs::write(&staging, bytes)?;
+    if let Err(e) = std::fs::rename(&staging, &path) {
+        let _ = std::fs::remove_file(&staging);
+        return Err(e.into());
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::test_mp3;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serve a single HTTP response on a local port, returning its URL.
+    fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(header.as_bytes());
+            let _ = stream.write_all(&body);
+        });
+        format!("http://{}/clip.mp3", addr)
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name(None, "Hmm.MP3"), Ok("Hmm.mp3".to_string()));
+        assert_eq!(
+            file_name(Some(" I Need Food "), "clip.mp3"),
+            Ok("I Need Food.mp3".to_string())
+        );
+        assert_eq!(file_name(None, "Hmm.ogg"), Ok("Hmm.ogg".to_string()));
+        assert!(file_name(None, "notes.txt").is_err());
+        assert!(file_name(None, "noextension").is_err());
+        assert!(file_name(Some("../escape"), "clip.mp3").is_err());
+        assert!(file_name(Some(".hidden"), "clip.mp3").is_err());
+        assert!(file_name(Some("   "), "clip.mp3").is_err());
+        assert!(file_name(Some(&"a".repeat(MAX_NAME_LEN + 1)), "clip.mp3").is_err());
+    }
+
+    #[test]
+    fn test_discord_limit() {
+        assert_eq!(discord_limit(PremiumTier::Tier0), 10 * 1024 * 1024);
+        assert_eq!(discord_limit(PremiumTier::Tier1), 10 * 1024 * 1024);
+        assert_eq!(discord_limit(PremiumTier::Tier3), 100 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_probe() {
+        // 100 frames of 1152 samples at 44.1 kHz.
+        let seconds = probe(test_mp3(100), "mp3").unwrap();
+        assert!((seconds - 100.0 * 1152.0 / 44100.0).abs() < 0.1);
+
+        assert!(probe(b"definitely not audio".to_vec(), "mp3").is_err());
+        assert!(probe(Vec::new(), "mp3").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let limits = UploadConfig {
+            max_bytes: 100_000,
+            max_seconds: 2.0,
+        };
+        assert!(validate(test_mp3(50), "mp3", &limits).is_ok());
+        // About 2.6 seconds.
+        assert!(validate(test_mp3(100), "mp3", &limits).is_err());
+        // About 240 KB.
+        assert!(validate(test_mp3(600), "mp3", &limits).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let client = reqwest::Client::new();
+        let body = test_mp3(10);
+        let url = serve(body.clone());
+        assert_eq!(download(&client, &url, 10_000).await.unwrap(), body);
+
+        let url = serve(test_mp3(10));
+        assert!(download(&client, &url, 1_000).await.is_err());
+    }
+
+    #[test]
+    fn test_save() {
+        let top_dir = std::env::temp_dir().join(format!("disquip-upload-{}", std::process::id()));
+        std::fs::create_dir_all(top_dir.join("a1")).unwrap();
+
+        let path = save(&top_dir, "a1", "Hmm.mp3", b"audio").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"audio");
+        assert!(save(&top_dir, "a1", "Hmm.mp3", b"other").is_err());
+        // Nothing is left behind in the top-level directory.
+        assert!(!top_dir.join(".upload-Hmm.mp3").exists());
+
+        std::fs::remove_dir_all(&top_dir).unwrap();
+    }
+}